@@ -1,15 +1,16 @@
 #![deny(unused_imports)]
 #![allow(dead_code)]
 use std::sync::{Arc, Mutex};
-use std::time::{Duration};
-use tokio::sync::mpsc::{UnboundedSender};
-use tokio::sync::mpsc::Sender;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicUsize, Ordering};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::Notify;
 use tokio::time::Instant;
+use crate::close_code;
 use crate::common::SignalMsg;
-
-const POLLING_QUEUE_SIZE: usize   = 30;
-const POLLING_EXPIRE_LIMIT: u64 = 3 * 60 * 1000;
-const WS_EXPIRE_LIMIT: u64 = 11 * 60 * 1000;
+use crate::config::{ConnectionOptions, OverflowPolicy};
+use crate::jwt::Claims;
+use crate::tenant;
 
 fn now() -> Instant {
     // SystemTime::now()
@@ -21,40 +22,187 @@ fn now() -> Instant {
 
 type Queue = Arc<Mutex<Vec<Arc<SignalMsg>>>>;
 
+// source of Client::generation
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(1);
+
+pub fn next_generation() -> u64 {
+    NEXT_GENERATION.fetch_add(1, Ordering::Relaxed)
+}
+
+pub enum SendResult {
+    Sent,
+    // the queue of the client is full, carries the message which was discarded
+    Dropped(Arc<SignalMsg>),
+    // the websocket client stayed over its send buffer limit and is being closed
+    Evicted,
+    Failed,
+}
+
+/// Bounded outbound queue of a websocket client.
+#[derive(Clone)]
+pub struct WsSender {
+    tx: Sender<String>,
+    pending_bytes: Arc<AtomicUsize>,
+    over_limit_since: Arc<Mutex<Option<Instant>>>,
+    evicted: Arc<AtomicBool>,
+    // close code the writer sends when it stops, 0 for none
+    close_code: Arc<AtomicU16>,
+    closing: Arc<Notify>,
+    evicting: Arc<Notify>,
+    options: ConnectionOptions,
+}
+
+impl WsSender {
+
+    pub fn channel(options: ConnectionOptions) -> (Self, Receiver<String>) {
+        let (tx, rx) = mpsc::channel(options.ws_queue_size);
+        let sender = Self {
+            tx,
+            pending_bytes: Arc::new(AtomicUsize::new(0)),
+            over_limit_since: Arc::new(Mutex::new(None)),
+            evicted: Arc::new(AtomicBool::new(false)),
+            close_code: Arc::new(AtomicU16::new(0)),
+            closing: Arc::new(Notify::new()),
+            evicting: Arc::new(Notify::new()),
+            options,
+        };
+        (sender, rx)
+    }
+
+    fn send(&self, msg: Arc<SignalMsg>, app: Option<&str>) -> SendResult {
+        if self.evicted.load(Ordering::Relaxed) || self.tx.is_closed() {
+            return SendResult::Failed
+        }
+        let text: String = match localize(&msg, app).as_ref().try_into() {
+            Ok(text) => text,
+            Err(_) => return SendResult::Failed,
+        };
+        let len = text.len();
+        // count the bytes before the writer can see the message, it subtracts them right after
+        let pending = self.pending_bytes.fetch_add(len, Ordering::Relaxed);
+        if pending + len <= self.options.ws_queue_bytes && self.tx.try_send(text).is_ok() {
+            *self.over_limit_since.lock().unwrap() = None;
+            return SendResult::Sent
+        }
+        self.pending_bytes.fetch_sub(len, Ordering::Relaxed);
+        let now = now();
+        let since = *self.over_limit_since.lock().unwrap().get_or_insert(now);
+        if now.duration_since(since) >= self.options.slow_consumer_timeout {
+            self.evicted.store(true, Ordering::Relaxed);
+            self.evicting.notify_one();
+            return SendResult::Evicted
+        }
+        SendResult::Dropped(msg)
+    }
+
+    /// Called by the writer once a message left the queue.
+    pub fn consumed(&self, len: usize) {
+        self.pending_bytes.fetch_sub(len, Ordering::Relaxed);
+    }
+
+    /// Ask the writer to stop.
+    pub fn close(&self) {
+        self.closing.notify_one();
+    }
+
+    /// Ask the writer to stop because the server shuts down.
+    pub fn go_away(&self) {
+        self.close_with(close_code::GOING_AWAY);
+    }
+
+    /// Ask the writer to stop and send the close code.
+    pub fn close_with(&self, code: u16) {
+        self.close_code.store(code, Ordering::Relaxed);
+        self.closing.notify_one();
+    }
+
+    /// Resolves when the writer should stop.
+    pub async fn closing(&self) {
+        self.closing.notified().await
+    }
+
+    /// Resolves when the client was evicted as a slow consumer.
+    pub async fn evicting(&self) {
+        self.evicting.notified().await
+    }
+
+    pub fn close_code(&self) -> Option<u16> {
+        match self.close_code.load(Ordering::Relaxed) {
+            0 => None,
+            code => Some(code),
+        }
+    }
+
+    async fn closed(&self) {
+        self.tx.closed().await
+    }
+}
+
 #[derive(Clone)]
 pub struct Client {
     pub peer_id: String,
+    // unique per connection and renewed by every poll, the hub only changes
+    // or removes an entry whose generation it was given
+    pub generation: u64,
     pub is_polling: bool,
+    // a websocket which was a polling client, its last long poll may still come back
+    pub upgraded: bool,
     pub timestamp: Instant,
+    // when the client joined, kept by polling clients across polls
+    pub created: Instant,
     pub msg_queue: Queue,
-    pub(crate) ws: Option<UnboundedSender<String>>,
-    pub http: Option<Sender<()>>
-
+    pub(crate) ws: Option<WsSender>,
+    pub http: Option<Sender<()>>,
+    // messages discarded because the polling queue was full
+    pub dropped: Arc<AtomicU64>,
+    // claims of the jwt the client connected with
+    pub claims: Option<Arc<Claims>>,
+    // the app of the client, its peer_id is then prefixed with the app id
+    pub app: Option<Arc<str>>,
+    // set when another connection of the peer id took over, a long poll which returns then gets 409
+    replaced: Arc<AtomicBool>,
+    options: ConnectionOptions,
 }
 
 impl Client {
 
-    pub fn new(peer_id: &str, sender: UnboundedSender<String>) -> Self {
+    pub fn new(peer_id: &str, sender: WsSender, options: ConnectionOptions) -> Self {
         Self {
             peer_id: peer_id.to_string(),
+            generation: next_generation(),
             is_polling: false,
+            upgraded: false,
             timestamp: now(),
+            created: now(),
             msg_queue: Arc::new(Mutex::new(vec![])),
             ws: Some(sender),
             http: None,
+            dropped: Arc::new(AtomicU64::new(0)),
+            claims: None,
+            app: None,
+            replaced: Arc::new(AtomicBool::new(false)),
+            options,
         }
     }
 
-    pub fn new_poll(peer_id: &str, sender: Sender<()>) -> Self {
+    pub fn new_poll(peer_id: &str, sender: Sender<()>, options: ConnectionOptions) -> Self {
         // let queue = Vec::with_capacity(20);
         // println!("Client::new_poll {} queue {:p}", peer_id, &queue);
         Self {
             peer_id: peer_id.to_string(),
+            generation: next_generation(),
             is_polling: true,
+            upgraded: false,
             timestamp: now(),
+            created: now(),
             msg_queue: Arc::new(Mutex::new(vec![])),
             ws: None,
             http: Some(sender),
+            dropped: Arc::new(AtomicU64::new(0)),
+            claims: None,
+            app: None,
+            replaced: Arc::new(AtomicBool::new(false)),
+            options,
         }
     }
 
@@ -62,9 +210,25 @@ impl Client {
         self.msg_queue.lock().unwrap().clear()
     }
 
-    pub fn switch_to_ws(&mut self, sender: UnboundedSender<String>) {
+    /// Upgrade a polling client to websocket, messages queued for the next poll are sent over the socket.
+    /// Returns the messages which did not fit into the queue of the socket.
+    pub fn switch_to_ws(&mut self, sender: WsSender) -> Vec<Arc<SignalMsg>> {
+        // a pending long poll keeps the old queue and finds it empty
+        let queue = std::mem::replace(&mut self.msg_queue, Arc::new(Mutex::new(vec![])));
+        let mut dropped = vec![];
+        for msg in queue.lock().unwrap().drain(..) {
+            if !matches!(sender.send(msg.clone(), self.app.as_deref()), SendResult::Sent) {
+                dropped.push(msg);
+            }
+        }
+        self.is_polling = false;
+        self.upgraded = true;
+        // the polling connection it was copied from is retired
+        self.replaced = Arc::new(AtomicBool::new(false));
         self.ws = Some(sender);
         self.http = None;
+        self.update_ts();
+        dropped
     }
 
     pub fn switch_to_http(&mut self, sender: Sender<()>) {
@@ -79,25 +243,40 @@ impl Client {
             ver: Some(ver),
             ..SignalMsg::default()
         };
-        self.send_message(Arc::new(msg)).await
+        !matches!(self.send_message(Arc::new(msg)).await, SendResult::Failed)
     }
 
-    pub async fn send_message(&mut self, msg: Arc<SignalMsg>) -> bool {
-        if self.is_polling {
-            return self.send_data_polling(msg).await;
-        }
-        self.send_msg_to_ws(msg).await
+    pub async fn send_message(&mut self, msg: Arc<SignalMsg>) -> SendResult {
+        self.send(msg)
 
         // Err(anyhow!("ws is null"))
     }
 
+    /// Queue the message for the next poll or for the websocket writer, never waits.
+    pub fn send(&self, msg: Arc<SignalMsg>) -> SendResult {
+        if self.is_polling {
+            return self.send_data_polling(msg);
+        }
+        self.send_msg_to_ws(msg)
+    }
+
     pub fn get_queued_msgs(&mut self) -> Vec<Arc<SignalMsg>> {
         // println!("{} self.msg_queue len {} {:p}", self.peer_id, self.msg_queue.len(), &self.msg_queue);
         // serde_json::to_string(&self.msg_queue).unwrap()
         // "".to_string()
-        let msgs = self.msg_queue.lock().unwrap().to_vec();
-        self.clear_queue();
-        msgs
+        self.msg_queue.lock().unwrap().drain(..)
+            .map(|msg| localize(&msg, self.app.as_deref()))
+            .collect()
+    }
+
+    /// Whether a long poll of the client is waiting for messages.
+    pub fn is_waiting(&self) -> bool {
+        self.http.as_ref().map(|http| !http.is_closed()).unwrap_or(false)
+    }
+
+    /// Whether another connection of the peer id took over, by an upgrade or a duplicate login.
+    pub fn is_replaced(&self) -> bool {
+        self.replaced.load(Ordering::Relaxed)
     }
 
     pub fn update_ts(&mut self) {
@@ -106,29 +285,68 @@ impl Client {
 
     pub fn is_expired(& self, now: Instant) -> bool {
         if self.is_polling {
-            return now.duration_since(self.timestamp) > Duration::from_millis(POLLING_EXPIRE_LIMIT)
+            return now.duration_since(self.timestamp) > self.options.polling_expire_limit
         }
-        return now.duration_since(self.timestamp) > Duration::from_millis(WS_EXPIRE_LIMIT)
+        now.duration_since(self.timestamp) > self.options.ws_expire_limit
     }
 
-    async fn send_data_polling(&mut self, msg: Arc<SignalMsg>) -> bool {
-        if self.msg_queue.lock().unwrap().len() >= POLLING_QUEUE_SIZE {
-            return true
+    fn send_data_polling(&self, msg: Arc<SignalMsg>) -> SendResult {
+        let mut dropped = None;
+        {
+            let mut queue = self.msg_queue.lock().unwrap();
+            if queue.len() >= self.options.polling_queue_size {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                match self.options.polling_overflow {
+                    OverflowPolicy::DropOldest => {
+                        dropped = Some(queue.remove(0));
+                        queue.push(msg);
+                    }
+                    OverflowPolicy::DropNewest | OverflowPolicy::Reject => {
+                        return SendResult::Dropped(msg)
+                    }
+                }
+            } else {
+                queue.push(msg);
+            }
         }
-        self.msg_queue.lock().unwrap().push(msg);
+        // a full channel already wakes the poll
         if let Some(http) = self.http.as_ref() {
-            if http.capacity() > 0 {
-                return http.send(()).await.is_ok();
+            if let Err(TrySendError::Closed(_)) = http.try_send(()) {
+                return SendResult::Failed
             }
         }
-        return true
+        match dropped {
+            None => SendResult::Sent,
+            Some(msg) => SendResult::Dropped(msg),
+        }
+    }
+
+    fn send_msg_to_ws(&self, msg: Arc<SignalMsg>) -> SendResult {
+        if let Some(ws) = self.ws.as_ref() {
+            return ws.send(msg, self.app.as_deref())
+        }
+        SendResult::Failed
+    }
+
+    /// Let the client know the server shuts down, a pending long poll returns its queued messages.
+    pub fn go_away(&self) {
+        if let Some(ws) = self.ws.as_ref() {
+            ws.go_away();
+        }
+        if let Some(http) = self.http.as_ref() {
+            let _ = http.try_send(());
+        }
     }
 
-    async  fn send_msg_to_ws(&self, msg: Arc<SignalMsg>) -> bool {
-        if let Some(ws) = self.ws.clone() {
-            return ws.send(msg.as_ref().try_into().unwrap()).is_ok()
+    /// Close the connection because the peer id connected again, a pending long poll returns.
+    pub fn kick(&self) {
+        self.replaced.store(true, Ordering::Relaxed);
+        if let Some(ws) = self.ws.as_ref() {
+            ws.close_with(close_code::DUPLICATE_LOGIN);
+        }
+        if let Some(http) = self.http.as_ref() {
+            let _ = http.try_send(());
         }
-        false
     }
 
     pub async fn close(&mut self) {
@@ -138,9 +356,29 @@ impl Client {
             }
         } else {
             if let Some(ws) = self.ws.clone() {
+                ws.close();
                 ws.closed().await
             }
         }
     }
 }
 
+// Messages carry the keys of the hub, a client of an app only sees the peer ids of its app.
+// The ack request of a signal is meant for the hub, the receiving peer does not see it.
+fn localize(msg: &Arc<SignalMsg>, app: Option<&str>) -> Arc<SignalMsg> {
+    let from = match (app, msg.from_peer_id.as_deref()) {
+        (Some(app), Some(from)) => Some(tenant::strip_app(from, app)),
+        _ => None,
+    };
+    if from.is_none() && msg.ack.is_none() {
+        return msg.clone()
+    }
+    let mut local = msg.as_ref().clone();
+    if let Some(from) = from {
+        local.from_peer_id = Some(from.to_string());
+    }
+    if local.ack.take().is_some() {
+        local.id = None;
+    }
+    Arc::new(local)
+}
@@ -0,0 +1,25 @@
+//! Close codes sent to websocket clients.
+//!
+//! | code | name           | meaning                                                        |
+//! |------|----------------|----------------------------------------------------------------|
+//! | 1001 | GOING_AWAY     | the server shuts down, reconnect to another node               |
+//! | 4001 | DUPLICATE_LOGIN| the peer id connected again, this connection was replaced      |
+//! | 4008 | SLOW_CONSUMER  | the client did not read its messages fast enough               |
+//!
+//! Token and rate limit checks run before the upgrade and are answered with
+//! 401/403 and 429. Older versions upgraded first and closed with 4000 for an
+//! invalid token and 5000 when rate limited, neither is sent anymore.
+
+pub const GOING_AWAY: u16 = 1001;
+pub const DUPLICATE_LOGIN: u16 = 4001;
+pub const SLOW_CONSUMER: u16 = 4008;
+
+/// Reason sent along with a close code.
+pub fn reason(code: u16) -> &'static str {
+    match code {
+        GOING_AWAY => "server going away",
+        DUPLICATE_LOGIN => "duplicate login",
+        SLOW_CONSUMER => "slow consumer",
+        _ => "",
+    }
+}
@@ -0,0 +1,460 @@
+#![deny(unused_imports)]
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use tklog::{error, warn};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use crate::common::SignalMsg;
+use crate::config::Cluster as ClusterConfig;
+use crate::hub::Hub;
+use crate::registry::{self, PeerRegistry};
+
+const RECONNECT_INTERVAL: u64 = 3;
+const DEFAULT_SYNC_INTERVAL: u64 = 60;
+
+// source of the ids of inbound connections
+static NEXT_INBOUND: AtomicU64 = AtomicU64::new(1);
+
+/// Frames exchanged between signal nodes, one JSON object per line.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ClusterMsg {
+    Hello { node: String, secret: Option<String> },
+    Join { peers: Vec<String> },
+    Leave { peers: Vec<String> },
+    Sync { peers: Vec<String> },
+    // app of the sender, None for the default namespace
+    Forward { to: String, app: Option<String>, msg: Box<SignalMsg> },
+}
+
+#[derive(Clone)]
+pub struct Cluster {
+    node_id: Arc<str>,
+    listen: u16,
+    secret: Option<String>,
+    sync_interval: u64,
+    nodes: Arc<Vec<String>>,
+    // peer id -> node id of the node which holds the peer
+    registry: Arc<dyn PeerRegistry>,
+    // node id -> outbound link to that node
+    links: Arc<Mutex<HashMap<String, UnboundedSender<String>>>>,
+    // node id -> id of the latest inbound connection from that node
+    inbound: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl Cluster {
+
+    pub fn new(config: &ClusterConfig, local_ip: &str) -> Self {
+        let node_id = match config.advertise.as_ref() {
+            Some(addr) => addr.clone(),
+            None => format!("{:}:{:}", local_ip, config.listen),
+        };
+        let nodes = config.nodes.iter()
+            .filter(|addr| **addr != node_id)
+            .cloned()
+            .collect();
+        let sync_interval = config.sync_interval.unwrap_or(DEFAULT_SYNC_INTERVAL);
+        // a node which misses three syncs in a row is considered gone
+        let node_ttl = Duration::from_secs(sync_interval * 3);
+        Self {
+            node_id: node_id.into(),
+            listen: config.listen,
+            secret: config.secret.clone(),
+            sync_interval,
+            nodes: Arc::new(nodes),
+            registry: registry::from_config(config.registry.as_ref(), node_ttl),
+            links: Arc::new(Mutex::new(HashMap::new())),
+            inbound: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// Start accepting frames from other nodes and connect to every configured node.
+    pub fn start(&self, hub: Hub) {
+        let cloned = self.clone();
+        let hub_cloned = hub.clone();
+        tokio::spawn(async move {
+            cloned.heartbeat_loop(hub_cloned).await;
+        });
+        let cloned = self.clone();
+        let hub_cloned = hub.clone();
+        tokio::spawn(async move {
+            if let Err(e) = cloned.listen(hub_cloned).await {
+                error!("cluster listen failed", e);
+            }
+        });
+        for node in self.nodes.iter() {
+            let cloned = self.clone();
+            let node = node.clone();
+            let hub = hub.clone();
+            tokio::spawn(async move {
+                cloned.connect_loop(node, hub).await;
+            });
+        }
+    }
+
+    /// Returns the remote node which holds the peer, if any.
+    pub async fn lookup(&self, peer_id: &str) -> Option<String> {
+        match self.registry.lookup(peer_id).await {
+            Ok(node) => node.filter(|node| *node != *self.node_id),
+            Err(e) => {
+                error!("registry lookup failed", e);
+                None
+            }
+        }
+    }
+
+    pub async fn num_remote_peers(&self) -> usize {
+        match self.registry.list().await {
+            Ok(peers) => peers.iter().filter(|(_, node)| *node != *self.node_id).count(),
+            Err(_) => 0,
+        }
+    }
+
+    pub async fn announce_join(&self, peer_id: &str) {
+        if let Err(e) = self.registry.register(peer_id, &self.node_id).await {
+            error!("registry register failed", e);
+        }
+        self.broadcast(&ClusterMsg::Join { peers: vec![peer_id.to_string()] });
+    }
+
+    pub async fn announce_leave(&self, peer_id: &str) {
+        if let Err(e) = self.registry.unregister(peer_id, &self.node_id).await {
+            error!("registry unregister failed", e);
+        }
+        self.broadcast(&ClusterMsg::Leave { peers: vec![peer_id.to_string()] });
+    }
+
+    /// Forward a message to the remote node which holds `to_peer_id`.
+    pub async fn forward(&self, to_peer_id: &str, msg: &SignalMsg, app: Option<&str>) -> bool {
+        let node = match self.lookup(to_peer_id).await {
+            None => return false,
+            Some(node) => node,
+        };
+        let frame = ClusterMsg::Forward { to: to_peer_id.to_string(), app: app.map(str::to_string), msg: Box::new(msg.clone()) };
+        match self.links.lock().unwrap().get(&node) {
+            None => false,
+            Some(link) => link.send(encode(&frame)).is_ok(),
+        }
+    }
+
+    fn broadcast(&self, frame: &ClusterMsg) {
+        let line = encode(frame);
+        for link in self.links.lock().unwrap().values() {
+            let _ = link.send(line.clone());
+        }
+    }
+
+    // Keep this node alive in the registry, also repairs entries lost on registry failover.
+    async fn heartbeat_loop(&self, hub: Hub) {
+        let mut timer = tokio::time::interval(Duration::from_secs(self.sync_interval));
+        loop {
+            timer.tick().await;
+            let peers = hub.local_peer_ids().await;
+            if let Err(e) = self.registry.heartbeat(&self.node_id, peers).await {
+                error!("registry heartbeat failed", e);
+            }
+        }
+    }
+
+    async fn connect_loop(&self, node: String, hub: Hub) {
+        loop {
+            match TcpStream::connect(node.as_str()).await {
+                Ok(stream) => {
+                    warn!("cluster connected to", node);
+                    let (tx, rx) = mpsc::unbounded_channel();
+                    self.links.lock().unwrap().insert(node.clone(), tx.clone());
+                    let _ = tx.send(encode(&ClusterMsg::Hello {
+                        node: self.node_id.to_string(),
+                        secret: self.secret.clone(),
+                    }));
+                    let sync = self.spawn_sync(tx, hub.clone());
+                    if let Err(e) = write_loop(stream, rx).await {
+                        warn!("cluster link to", node, "closed:", e);
+                    }
+                    sync.abort();
+                    self.links.lock().unwrap().remove(&node);
+                }
+                Err(e) => {
+                    warn!("cluster connect to", node, "failed:", e);
+                }
+            }
+            tokio::time::sleep(Duration::from_secs(RECONNECT_INTERVAL)).await;
+        }
+    }
+
+    // Periodically send the full list of local peers so remote tables recover from lost frames.
+    fn spawn_sync(&self, tx: UnboundedSender<String>, hub: Hub) -> tokio::task::JoinHandle<()> {
+        let interval = self.sync_interval;
+        tokio::spawn(async move {
+            let mut timer = tokio::time::interval(Duration::from_secs(interval));
+            loop {
+                timer.tick().await;
+                let peers = hub.local_peer_ids().await;
+                if tx.send(encode(&ClusterMsg::Sync { peers })).is_err() {
+                    break;
+                }
+            }
+        })
+    }
+
+    async fn listen(&self, hub: Hub) -> std::io::Result<()> {
+        let listener = TcpListener::bind(("::", self.listen)).await?;
+        warn!("cluster listening on", listener.local_addr()?, "node", self.node_id);
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            let cloned = self.clone();
+            let hub = hub.clone();
+            tokio::spawn(async move {
+                if let Err(e) = cloned.read_loop(stream, hub).await {
+                    warn!("cluster connection from", addr, "closed:", e);
+                }
+            });
+        }
+    }
+
+    async fn read_loop(&self, stream: TcpStream, mut hub: Hub) -> anyhow::Result<()> {
+        let mut lines = BufReader::new(stream).lines();
+        let node = match lines.next_line().await?.map(|line| serde_json::from_str(&line)) {
+            Some(Ok(ClusterMsg::Hello { node, secret })) => {
+                if !secret_matches(secret.as_deref(), self.secret.as_deref()) {
+                    return Err(anyhow::anyhow!("node {} secret not match", node));
+                }
+                node
+            }
+            _ => return Err(anyhow::anyhow!("expect hello frame")),
+        };
+        let id = NEXT_INBOUND.fetch_add(1, Ordering::Relaxed);
+        self.inbound.lock().unwrap().insert(node.clone(), id);
+        let result = async {
+            while let Some(line) = lines.next_line().await? {
+                match serde_json::from_str::<ClusterMsg>(&line) {
+                    Ok(frame) => {
+                        if let Err(e) = self.handle_frame(&node, frame, &mut hub).await {
+                            error!("cluster handle frame failed", e);
+                        }
+                    }
+                    Err(e) => error!("cluster parse frame failed", e),
+                }
+            }
+            Ok(())
+        }.await;
+        // the node is gone, so are its peers, unless it connected again meanwhile,
+        // a half open connection may be noticed after the new one synced the peers
+        let latest = {
+            let mut inbound = self.inbound.lock().unwrap();
+            let latest = inbound.get(&node) == Some(&id);
+            if latest {
+                inbound.remove(&node);
+            }
+            latest
+        };
+        if latest {
+            if let Err(e) = self.registry.remove_node(&node).await {
+                error!("registry remove node failed", e);
+            }
+        }
+        result
+    }
+
+    async fn handle_frame(&self, node: &str, frame: ClusterMsg, hub: &mut Hub) -> anyhow::Result<()> {
+        match frame {
+            ClusterMsg::Join { peers } => {
+                for peer in peers {
+                    self.registry.register(&peer, node).await?;
+                }
+            }
+            ClusterMsg::Leave { peers } => {
+                for peer in peers {
+                    self.registry.unregister(&peer, node).await?;
+                }
+            }
+            ClusterMsg::Sync { peers } => {
+                self.registry.heartbeat(node, peers).await?;
+            }
+            ClusterMsg::Forward { to, app, msg } => {
+                hub.process_forwarded(&to, *msg, app.as_deref()).await;
+            }
+            ClusterMsg::Hello { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+async fn write_loop(mut stream: TcpStream, mut rx: UnboundedReceiver<String>) -> std::io::Result<()> {
+    while let Some(line) = rx.recv().await {
+        stream.write_all(line.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+// Constant time, so the secret cannot be guessed from how long the check takes.
+// A node without a secret accepts nobody, forwarded signals skip every check of clients.
+fn secret_matches(received: Option<&str>, expected: Option<&str>) -> bool {
+    match (received, expected) {
+        (Some(received), Some(expected)) => received.as_bytes().ct_eq(expected.as_bytes()).into(),
+        _ => false,
+    }
+}
+
+fn encode(frame: &ClusterMsg) -> String {
+    let mut line = serde_json::to_string(frame).unwrap();
+    line.push('\n');
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::io::AsyncReadExt;
+    use crate::client::{Client, WsSender};
+    use crate::config::Connection;
+    use crate::filter::SignalFilter;
+    use crate::testing::PEER;
+    use super::*;
+
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    fn node(listen: u16, nodes: &[String], secret: &str) -> (Cluster, Hub) {
+        let config = ClusterConfig {
+            enable: true,
+            listen,
+            advertise: Some(format!("127.0.0.1:{}", listen)),
+            nodes: nodes.to_vec(),
+            secret: Some(secret.to_string()),
+            sync_interval: Some(1),
+            registry: None,
+        };
+        let cluster = Cluster::new(&config, "127.0.0.1");
+        let hub = Hub::new(Connection::default().options().unwrap(), SignalFilter::new(None), None, Some(cluster.clone()));
+        cluster.start(hub.clone());
+        (cluster, hub)
+    }
+
+    #[test]
+    fn frames_are_one_json_line() {
+        let frame = ClusterMsg::Forward {
+            to: PEER.to_string(),
+            app: Some("app1".to_string()),
+            msg: Box::new(SignalMsg { action: Some("signal".to_string()), data: Some(json!({"sdp": "offer"})), ..SignalMsg::default() }),
+        };
+        let line = encode(&frame);
+        assert!(line.ends_with('\n') && !line.trim_end().contains('\n'));
+        match serde_json::from_str(&line).unwrap() {
+            ClusterMsg::Forward { to, app, msg } => {
+                assert_eq!(to, PEER);
+                assert_eq!(app.as_deref(), Some("app1"));
+                assert_eq!(msg.data, Some(json!({"sdp": "offer"})));
+            }
+            frame => panic!("decoded {:?}", frame),
+        }
+        assert!(matches!(serde_json::from_str(r#"{"type":"hello","node":"n1","secret":null}"#).unwrap(), ClusterMsg::Hello { secret: None, .. }));
+    }
+
+    #[tokio::test]
+    async fn signals_reach_peers_of_another_node() {
+        let ports = [free_port(), free_port()];
+        let nodes: Vec<String> = ports.iter().map(|port| format!("127.0.0.1:{}", port)).collect();
+        let (first, mut first_hub) = node(ports[0], &nodes, "secret");
+        let (_, second_hub) = node(ports[1], &nodes, "secret");
+        let (tx, mut rx) = WsSender::channel(second_hub.options());
+        assert!(second_hub.login(Client::new(PEER, tx, second_hub.options())).await);
+        let (tx, _) = WsSender::channel(first_hub.options());
+        assert!(first_hub.login(Client::new("sender01", tx, first_hub.options())).await);
+
+        // learnt from the join or else from the next sync, forwarded once the first node connected back
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while first.lookup(PEER).await.as_deref() != Some(nodes[1].as_str())
+                || !first.links.lock().unwrap().contains_key(&nodes[1]) {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        }).await.unwrap();
+        let msg = SignalMsg {
+            action: Some("signal".to_string()),
+            to_peer_id: Some(PEER.to_string()),
+            data: Some(json!({"sdp": "offer"})),
+            ..SignalMsg::default()
+        };
+        first_hub.process_message(Arc::new(msg), "sender01", None).await;
+        let received: serde_json::Value = serde_json::from_str(&tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap()).unwrap();
+        assert_eq!(received, json!({"action": "signal", "from_peer_id": "sender01", "data": {"sdp": "offer"}}));
+    }
+
+    #[tokio::test]
+    async fn nodes_with_another_secret_are_refused() {
+        let port = free_port();
+        let nodes = vec![format!("127.0.0.1:{}", port)];
+        let (cluster, _hub) = node(port, &nodes, "secret");
+        let mut stream = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(stream) = TcpStream::connect(nodes[0].as_str()).await {
+                    return stream
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        }).await.unwrap();
+        stream.write_all(encode(&ClusterMsg::Hello { node: "127.0.0.1:1".to_string(), secret: Some("guess".to_string()) }).as_bytes()).await.unwrap();
+        stream.write_all(encode(&ClusterMsg::Join { peers: vec![PEER.to_string()] }).as_bytes()).await.unwrap();
+        // the connection is closed without reading the join
+        let mut buf = [0u8; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await.unwrap();
+        assert!(matches!(read, Ok(0) | Err(_)));
+        assert_eq!(cluster.lookup(PEER).await, None);
+    }
+
+    #[tokio::test]
+    async fn old_connection_of_a_node_does_not_remove_its_peers() {
+        let port = free_port();
+        let nodes = vec![format!("127.0.0.1:{}", port)];
+        let (cluster, _hub) = node(port, &nodes, "secret");
+        let connect = || async {
+            let mut stream = tokio::time::timeout(Duration::from_secs(5), async {
+                loop {
+                    if let Ok(stream) = TcpStream::connect(nodes[0].as_str()).await {
+                        return stream
+                    }
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+            }).await.unwrap();
+            stream.write_all(encode(&ClusterMsg::Hello { node: "127.0.0.1:1".to_string(), secret: Some("secret".to_string()) }).as_bytes()).await.unwrap();
+            stream
+        };
+        let wait_for = |expected: Option<&'static str>| {
+            let cluster = cluster.clone();
+            async move {
+                tokio::time::timeout(Duration::from_secs(5), async {
+                    while cluster.lookup(PEER).await.as_deref() != expected {
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                    }
+                }).await.unwrap();
+            }
+        };
+        let mut old = connect().await;
+        old.write_all(encode(&ClusterMsg::Join { peers: vec![PEER.to_string()] }).as_bytes()).await.unwrap();
+        wait_for(Some("127.0.0.1:1")).await;
+        let old_id = cluster.inbound.lock().unwrap()["127.0.0.1:1"];
+        // the node reconnects after a blip and syncs its peers before the old connection is noticed closed
+        let mut new = connect().await;
+        new.write_all(encode(&ClusterMsg::Sync { peers: vec![PEER.to_string()] }).as_bytes()).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while cluster.inbound.lock().unwrap()["127.0.0.1:1"] == old_id {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        }).await.unwrap();
+        drop(old);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(cluster.lookup(PEER).await.as_deref(), Some("127.0.0.1:1"));
+        drop(new);
+        wait_for(None).await;
+    }
+}
@@ -1,16 +1,18 @@
 #![deny(unused_imports)]
 use std::fmt::{Debug, Formatter};
+use std::time::Duration;
 use async_trait::async_trait;
 use axum::body::Bytes;
 use axum::extract::{FromRequest, Request};
 use axum::Json;
 use axum::response::{IntoResponse, Response};
-use http::StatusCode;
+use http::{header, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use thiserror::Error;
 
 use crate::stats::Info;
+use crate::reload::ReloadReport;
 
 #[serde_with::skip_serializing_none]
 #[derive(Serialize, Deserialize, Clone)]
@@ -23,6 +25,31 @@ pub struct SignalMsg {
     pub reason: Option<String>,
     pub data: Option<Value>,
     pub ver: Option<i32>,
+    // chosen by the sender, echoed in the ack
+    pub id: Option<Value>,
+    // the sender wants an ack for this signal or batch
+    pub ack: Option<bool>,
+    // outcome of a signal, in acks
+    pub status: Option<Delivery>,
+    // outcome of every item of a batch, in acks
+    pub results: Option<Vec<Delivery>>,
+}
+
+/// What became of a signal, reported to senders which asked for an ack.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Delivery {
+    // handed to the websocket of the peer
+    Delivered,
+    // waiting for the next poll of the peer, or for the peer to connect again
+    Queued,
+    // also sent again for a queued signal when the peer did not connect again in time
+    PeerNotFound,
+    RateLimited,
+    // the queue of the peer is full
+    Dropped,
+    // signals between the two peers are suppressed after a reject or peer not found
+    Filtered,
 }
 
 impl Debug for SignalMsg {
@@ -52,6 +79,10 @@ impl Default for SignalMsg {
             reason: None,
             data: None,
             ver: None,
+            id: None,
+            ack: None,
+            status: None,
+            results: None,
         }
     }
 }
@@ -88,6 +119,20 @@ impl SignalMsg {
         }
         None
     }
+
+    pub fn wants_ack(&self) -> bool {
+        self.ack.unwrap_or(false)
+    }
+
+    /// Whether the hub wrote the message about a peer, its from_peer_id is not the sender:
+    /// acks, dropped and reject notices, which carry a reason, and the empty signal of peer not found.
+    pub fn is_notice(&self) -> bool {
+        match self.action.as_deref() {
+            Some("ack" | "dropped") => true,
+            Some("signal") => self.data.is_none(),
+            _ => self.reason.is_some(),
+        }
+    }
 }
 
 pub struct ValidatedBody(pub Bytes);
@@ -117,7 +162,11 @@ pub enum ApiResponse {
     SignalVersion(i32),
     Count(String),
     Version(String),
-    Info(Info),
+    Info(Box<Info>),
+    Reload(ReloadReport),
+    Metrics(String),
+    // content type and body
+    Profile(&'static str, Vec<u8>),
 }
 
 // 这让 `ApiResponse` 可以被自动转换成一个 `axum Response`。
@@ -136,6 +185,9 @@ impl IntoResponse for ApiResponse {
             Self::Count(count) =>  (StatusCode::OK, count).into_response(),
             Self::Version(ver) =>  (StatusCode::OK, ver).into_response(),
             Self::Info(info) => (StatusCode::OK, Json(info)).into_response(),
+            Self::Reload(report) => (StatusCode::OK, Json(report)).into_response(),
+            Self::Profile(content_type, body) => (StatusCode::OK, [(header::CONTENT_TYPE, content_type)], body).into_response(),
+            Self::Metrics(text) => (StatusCode::OK, [(header::CONTENT_TYPE, "text/plain; version=0.0.4")], text).into_response(),
         }
     }
 }
@@ -146,7 +198,11 @@ pub enum ApiError {
     Unauthorised,
     InternalServerError,
     CONFLICT,
-    TokenInvalid
+    TokenInvalid,
+    // how long the client should wait
+    TooManyRequests(Duration),
+    // the app reached its max clients
+    ServiceUnavailable,
 }
 
 impl IntoResponse for ApiError {
@@ -158,7 +214,12 @@ impl IntoResponse for ApiError {
             Self::Unauthorised => (StatusCode::UNAUTHORIZED).into_response(),
             Self::CONFLICT => (StatusCode::CONFLICT).into_response(),
             Self::InternalServerError => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
-            Self::TokenInvalid => (StatusCode::UNAUTHORIZED).into_response()
+            Self::TokenInvalid => (StatusCode::FORBIDDEN).into_response(),
+            Self::ServiceUnavailable => (StatusCode::SERVICE_UNAVAILABLE).into_response(),
+            Self::TooManyRequests(retry_after) => {
+                let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, secs.max(1).to_string())]).into_response()
+            }
         }
     }
 }
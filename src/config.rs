@@ -1,9 +1,12 @@
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use serde::Deserialize;
+use std::collections::HashSet;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
+use std::time::Duration;
+use crate::{limiter, offline, origin};
 
 #[derive(Deserialize, Debug, Clone)]
 pub enum LogLevel {
@@ -35,12 +38,55 @@ pub struct TlsItem {
     pub port: u16,
     pub cert: String,
     pub key: String,
+    pub server_names: Option<Vec<String>>,  // SNI hostnames served by this cert, defaults to the DNS names in the cert
+    pub default: Option<bool>,              // served when no hostname matches, implied for the only cert of a port
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Ratelimit {
     pub enable: bool,
-    pub max_rate: u64,
+    pub max_rate: Option<u64>,              // new connections per second of the whole server
+    pub per_ip: Option<KeyedRatelimit>,
+    pub per_peer: Option<KeyedRatelimit>,
+    pub allowlist: Option<Vec<String>>,     // ips or cidr ranges which are never limited
+    pub real_ip_header: Option<String>,     // e.g. X-Forwarded-For, when behind a proxy
+    pub trusted_proxies: Option<Vec<String>>,   // ips or cidr ranges of the proxies which set real_ip_header
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Origin {
+    pub enable: bool,
+    pub allowlist: Vec<String>,             // e.g. https://example.com, https://*.example.com, http://localhost:*
+    pub allow_missing: Option<bool>,        // requests without an Origin header, e.g. native apps, default true
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Tenants {
+    pub enable: bool,
+    pub allow_default: Option<bool>,        // clients without an app id share the default namespace, default true
+    pub apps: Vec<App>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct App {
+    pub id: String,                         // sent by clients as the app query parameter
+    pub token: Option<String>,              // key of legacy tokens of this app
+    pub keys: Option<Vec<SecurityKey>>,     // keys of v2 tokens of this app
+    pub max_timestamp_age: Option<u64>,     // default 3600
+    pub max_clients: Option<usize>,         // connected clients of this app on this node
+    pub ratelimit: Option<KeyedRatelimit>,  // connects and messages of the whole app
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct KeyedRatelimit {
+    pub connect: Option<Rate>,              // connection attempts of one ip or peer id
+    pub message: Option<Rate>,              // messages of one ip or peer id
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Rate {
+    pub rate: f64,                          // per second
+    pub burst: Option<u64>,                 // defaults to rate
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -64,8 +110,234 @@ pub enum Port {
 #[derive(Deserialize, Debug, Clone)]
 pub struct Security {
     pub enable: bool,
+    #[serde(default)]
+    pub mode: SecurityMode,
+    #[serde(default)]
     pub maxTimeStampAge: u64,
-    pub token: String,
+    pub token: Option<String>,              // key of the legacy HMAC-MD5 tokens, accepted while it is set
+    pub keys: Option<Vec<SecurityKey>>,     // HMAC-SHA256 keys of v2 tokens
+    pub jwt: Option<Jwt>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SecurityMode {
+    #[default]
+    Token,                                  // signed tokens of utils::check_token
+    Jwt,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Jwt {
+    pub secret: Option<String>,             // HS256 key
+    pub public_keys: Option<Vec<JwtKey>>,   // RS256 or ES256 public keys in PEM files
+    pub jwks: Option<String>,               // JWKS file, re-read on reload
+    pub audience: Option<Vec<String>>,      // accepted aud, not checked when empty
+    pub issuer: Option<String>,
+    pub id_claim: Option<String>,           // claim which must equal the peer id, default sub
+    pub leeway: Option<u64>,                // seconds of clock skew allowed for exp and nbf
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct JwtKey {
+    pub kid: Option<String>,
+    pub path: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SecurityKey {
+    pub id: String,                         // key id carried by the token, must not contain '.'
+    pub secret: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Cluster {
+    pub enable: bool,
+    pub listen: u16,                    // port for node-to-node traffic
+    pub advertise: Option<String>,      // address other nodes use to reach this node, default local_ip:listen
+    pub nodes: Vec<String>,             // addresses of all nodes in the cluster
+    pub secret: Option<String>,
+    pub sync_interval: Option<u64>,     // seconds between full peer list syncs
+    pub registry: Option<Registry>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum RegistryBackend {
+    Memory,
+    Socket,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Registry {
+    pub backend: RegistryBackend,
+    pub path: Option<String>,           // unix socket path of the socket backend
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Filter {
+    pub capacity: Option<usize>,        // max number of suppressed peer pairs
+    pub ttl: Option<u64>,               // seconds a pair stays suppressed
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Offline {
+    pub enable: bool,
+    pub ttl: Option<u64>,               // seconds signals are held for a disconnected peer
+    pub size: Option<usize>,            // max signals held per peer
+    pub capacity: Option<usize>,        // max number of peers held at once
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    #[default]
+    Desktop,
+    Mobile,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    DropOldest,             // discard the oldest queued message
+    #[default]
+    DropNewest,             // discard the incoming message
+    Reject,                 // discard the incoming message and send a reject to its sender
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateLogin {
+    Reject,                 // refuse the new connection with 409
+    #[default]
+    Kick,                   // close the old connection with code 4001 and register the new one
+    Allow,                  // keep both connections, every message of the peer id is sent to each of them
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Connection {
+    pub profile: Option<Profile>,           // presets, each value below overrides its preset
+    pub polling_queue_size: Option<usize>,  // max queued messages of a polling client
+    pub polling_overflow: Option<OverflowPolicy>, // what to do when the polling queue is full
+    pub polling_expire_limit: Option<u64>,  // seconds a polling client may be idle
+    pub ws_expire_limit: Option<u64>,       // seconds a websocket client may be idle
+    pub ws_queue_size: Option<usize>,       // max messages waiting to be written to a websocket client
+    pub ws_queue_bytes: Option<usize>,      // max bytes waiting to be written to a websocket client
+    pub slow_consumer_timeout: Option<u64>, // seconds a websocket client may stay over its queue limits before it is closed
+    pub polling_timeout: Option<u64>,       // seconds a long poll waits for messages
+    pub check_interval: Option<u64>,        // seconds between sweeps of expired clients
+    pub duplicate_login: Option<DuplicateLogin>, // what to do when a peer id connects while it is connected
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionOptions {
+    pub polling_queue_size: usize,
+    pub polling_overflow: OverflowPolicy,
+    pub polling_expire_limit: Duration,
+    pub ws_expire_limit: Duration,
+    pub ws_queue_size: usize,
+    pub ws_queue_bytes: usize,
+    pub slow_consumer_timeout: Duration,
+    pub polling_timeout: Duration,
+    pub check_interval: Duration,
+    pub duplicate_login: DuplicateLogin,
+}
+
+impl Profile {
+    fn options(&self) -> ConnectionOptions {
+        match self {
+            Profile::Desktop => ConnectionOptions {
+                polling_queue_size: 30,
+                polling_overflow: OverflowPolicy::DropNewest,
+                polling_expire_limit: Duration::from_secs(3 * 60),
+                ws_expire_limit: Duration::from_secs(11 * 60),
+                ws_queue_size: 256,
+                ws_queue_bytes: 1024 * 1024,
+                slow_consumer_timeout: Duration::from_secs(10),
+                polling_timeout: Duration::from_secs(60),
+                check_interval: Duration::from_secs(6 * 60),
+                duplicate_login: DuplicateLogin::Kick,
+            },
+            // mobile networks stall for long periods while apps are in the background
+            Profile::Mobile => ConnectionOptions {
+                polling_queue_size: 60,
+                polling_overflow: OverflowPolicy::DropNewest,
+                polling_expire_limit: Duration::from_secs(10 * 60),
+                ws_expire_limit: Duration::from_secs(30 * 60),
+                ws_queue_size: 256,
+                ws_queue_bytes: 1024 * 1024,
+                slow_consumer_timeout: Duration::from_secs(30),
+                polling_timeout: Duration::from_secs(60),
+                check_interval: Duration::from_secs(5 * 60),
+                duplicate_login: DuplicateLogin::Kick,
+            },
+        }
+    }
+}
+
+impl Connection {
+    pub fn options(&self) -> Result<ConnectionOptions> {
+        let mut options = self.profile.unwrap_or_default().options();
+        if let Some(size) = self.polling_queue_size {
+            options.polling_queue_size = size;
+        }
+        if let Some(policy) = self.polling_overflow {
+            options.polling_overflow = policy;
+        }
+        if let Some(secs) = self.polling_expire_limit {
+            options.polling_expire_limit = Duration::from_secs(secs);
+        }
+        if let Some(secs) = self.ws_expire_limit {
+            options.ws_expire_limit = Duration::from_secs(secs);
+        }
+        if let Some(size) = self.ws_queue_size {
+            options.ws_queue_size = size;
+        }
+        if let Some(bytes) = self.ws_queue_bytes {
+            options.ws_queue_bytes = bytes;
+        }
+        if let Some(secs) = self.slow_consumer_timeout {
+            options.slow_consumer_timeout = Duration::from_secs(secs);
+        }
+        if let Some(secs) = self.polling_timeout {
+            options.polling_timeout = Duration::from_secs(secs);
+        }
+        if let Some(secs) = self.check_interval {
+            options.check_interval = Duration::from_secs(secs);
+        }
+        if let Some(policy) = self.duplicate_login {
+            options.duplicate_login = policy;
+        }
+        if options.polling_queue_size == 0 {
+            return Err(anyhow!("connection.polling_queue_size must be greater than 0"))
+        }
+        if options.ws_queue_size == 0 || options.ws_queue_bytes == 0 {
+            return Err(anyhow!("connection.ws_queue_size and connection.ws_queue_bytes must be greater than 0"))
+        }
+        if options.polling_timeout.is_zero() || options.check_interval.is_zero() {
+            return Err(anyhow!("connection.polling_timeout and connection.check_interval must be greater than 0"))
+        }
+        // a client waiting in a long poll must not expire meanwhile
+        if options.polling_timeout >= options.polling_expire_limit {
+            return Err(anyhow!("connection.polling_timeout must be less than connection.polling_expire_limit"))
+        }
+        if options.ws_expire_limit.is_zero() {
+            return Err(anyhow!("connection.ws_expire_limit must be greater than 0"))
+        }
+        Ok(options)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Shutdown {
+    pub drain_timeout: Option<u64>,     // seconds to wait for clients to leave before exiting
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CertWatch {
+    pub enable: bool,                   // reload certificates when their files change, on by default
+    pub check_interval: Option<u64>,    // seconds between checks of the cert and key files
+    pub expiry_warn_days: Option<u64>,  // warn when a certificate expires within this many days
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -74,9 +346,44 @@ pub struct Config {
     pub port: Option<Port>,
     pub tls: Option<Tls>,
     pub ratelimit: Option<Ratelimit>,
+    pub origin: Option<Origin>,
+    pub tenants: Option<Tenants>,
     pub stats: Option<Stats>,
     pub compression: Option<Compression>,
     pub security: Option<Security>,
+    pub cluster: Option<Cluster>,
+    pub filter: Option<Filter>,
+    pub offline: Option<Offline>,
+    pub connection: Option<Connection>,
+    pub shutdown: Option<Shutdown>,
+    pub cert_watch: Option<CertWatch>,
+}
+
+impl Config {
+    pub fn connection_options(&self) -> Result<ConnectionOptions> {
+        self.connection.clone().unwrap_or_default().options()
+    }
+
+    pub fn tls_items(&self) -> Vec<TlsItem> {
+        match self.tls.clone() {
+            None => vec![],
+            Some(Tls::TlsItem(item)) => vec![item],
+            Some(Tls::TlsItems(items)) => items,
+        }
+    }
+
+    /// Tls items grouped by the port they are served on, ordered by port.
+    pub fn tls_ports(&self) -> Vec<(u16, Vec<TlsItem>)> {
+        let mut ports: Vec<(u16, Vec<TlsItem>)> = Vec::new();
+        for item in self.tls_items() {
+            match ports.iter_mut().find(|(port, _)| *port == item.port) {
+                Some((_, items)) => items.push(item),
+                None => ports.push((item.port, vec![item])),
+            }
+        }
+        ports.sort_by_key(|(port, _)| *port);
+        ports
+    }
 }
 
 pub fn parse<P: AsRef<Path>>(path: P) -> Result<Config> {
@@ -89,6 +396,113 @@ pub fn parse<P: AsRef<Path>>(path: P) -> Result<Config> {
 
     // 将字符串解析为Rust结构体
     let config: Config = serde_yaml::from_str(&content)?;
+    let connection = config.connection_options()?;
+    if let Some(ratelimit) = config.ratelimit.as_ref() {
+        if ratelimit.enable && ratelimit.max_rate == Some(0) {
+            return Err(anyhow!("ratelimit.max_rate must be greater than 0"))
+        }
+        for (name, keyed) in [("per_ip", ratelimit.per_ip.as_ref()), ("per_peer", ratelimit.per_peer.as_ref())] {
+            for rate in keyed.iter().flat_map(|k| [k.connect.as_ref(), k.message.as_ref()]).flatten() {
+                if rate.rate <= 0.0 || rate.burst == Some(0) {
+                    return Err(anyhow!("ratelimit.{} rate and burst must be greater than 0", name))
+                }
+            }
+        }
+        for net in ratelimit.allowlist.iter().flatten().chain(ratelimit.trusted_proxies.iter().flatten()) {
+            limiter::IpNet::parse(net)?;
+        }
+        // without them any client could pick its own ip
+        if ratelimit.real_ip_header.is_some() && ratelimit.trusted_proxies.as_ref().map(|p| p.is_empty()).unwrap_or(true) {
+            return Err(anyhow!("ratelimit.real_ip_header needs ratelimit.trusted_proxies"))
+        }
+    }
+    if let Some(origin) = config.origin.as_ref() {
+        for pattern in origin.allowlist.iter().filter(|o| o.trim() != "*") {
+            origin::OriginPattern::parse(pattern)?;
+        }
+    }
+    if let Some(cert_watch) = config.cert_watch.as_ref() {
+        if cert_watch.check_interval == Some(0) {
+            return Err(anyhow!("cert_watch.check_interval must be greater than 0"))
+        }
+    }
+    if let Some(filter) = config.filter.as_ref() {
+        if filter.ttl == Some(0) || filter.capacity == Some(0) {
+            return Err(anyhow!("filter.ttl and filter.capacity must be greater than 0"))
+        }
+    }
+    if let Some(cluster) = config.cluster.as_ref() {
+        if cluster.enable && cluster.sync_interval == Some(0) {
+            return Err(anyhow!("cluster.sync_interval must be greater than 0"))
+        }
+        // the cluster port accepts forwarded signals as if a client sent them
+        if cluster.enable && cluster.secret.as_deref().map(str::is_empty).unwrap_or(true) {
+            return Err(anyhow!("cluster.secret is required in cluster mode"))
+        }
+    }
+    if let Some(offline) = config.offline.as_ref() {
+        if offline.enable && (offline.ttl == Some(0) || offline.size == Some(0) || offline.capacity == Some(0)) {
+            return Err(anyhow!("offline ttl, size and capacity must be greater than 0"))
+        }
+        // held signals are delivered at once, more than fit into a polling queue would be dropped
+        if offline.enable && offline.size.unwrap_or(offline::DEFAULT_SIZE) > connection.polling_queue_size {
+            return Err(anyhow!("offline.size must not be greater than connection.polling_queue_size"))
+        }
+    }
+    if let Some(tenants) = config.tenants.as_ref() {
+        let mut ids = HashSet::new();
+        for app in tenants.apps.iter() {
+            // also used as a metrics label and as the prefix of peer keys
+            let valid = !app.id.is_empty() && app.id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if !valid || !ids.insert(app.id.as_str()) {
+                return Err(anyhow!("invalid or duplicate app id {:?} in tenants", app.id))
+            }
+            if app.token.is_none() && app.keys.as_ref().map(|k| k.is_empty()).unwrap_or(true) {
+                return Err(anyhow!("app {} needs a token or keys", app.id))
+            }
+            if app.max_timestamp_age == Some(0) {
+                return Err(anyhow!("max_timestamp_age of app {} must be greater than 0", app.id))
+            }
+            for key in app.keys.iter().flatten() {
+                if key.id.is_empty() || key.id.contains('.') || key.secret.is_empty() {
+                    return Err(anyhow!("invalid key {:?} of app {}", key.id, app.id))
+                }
+            }
+            for rate in app.ratelimit.iter().flat_map(|r| [r.connect.as_ref(), r.message.as_ref()]).flatten() {
+                if rate.rate <= 0.0 || rate.burst == Some(0) {
+                    return Err(anyhow!("rate and burst of app {} must be greater than 0", app.id))
+                }
+            }
+        }
+    }
+    if let Some(security) = config.security.as_ref() {
+        for key in security.keys.iter().flatten() {
+            if key.id.is_empty() || key.id.contains('.') || key.secret.is_empty() {
+                return Err(anyhow!("security.keys need an id without '.' and a secret"))
+            }
+        }
+        match security.mode {
+            SecurityMode::Token => {
+                if security.enable && security.token.is_none() && security.keys.as_ref().map(|k| k.is_empty()).unwrap_or(true) {
+                    return Err(anyhow!("security needs a token or keys when enabled"))
+                }
+                // only jwt mode may leave it out, every token would be too old
+                if security.enable && security.maxTimeStampAge == 0 {
+                    return Err(anyhow!("security.maxTimeStampAge must be greater than 0 in token mode"))
+                }
+            }
+            SecurityMode::Jwt => {
+                if security.enable && security.jwt.is_none() {
+                    return Err(anyhow!("security.jwt is required in jwt mode"))
+                }
+            }
+        }
+    }
+    for (port, items) in config.tls_ports() {
+        if items.iter().filter(|item| item.default.unwrap_or(false)).count() > 1 {
+            return Err(anyhow!("tls port {} has more than one default cert", port))
+        }
+    }
 
     // println!("config: {:?}", config);
 
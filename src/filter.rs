@@ -0,0 +1,126 @@
+#![deny(unused_imports)]
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use dashmap::DashMap;
+use tokio::time::{Duration, Instant};
+use crate::config::Filter as FilterConfig;
+
+const DEFAULT_CAPACITY: usize = 6000;
+const DEFAULT_TTL: u64 = 5 * 60;
+
+/// Pairs of peers whose signals are suppressed, shared by every clone of the hub.
+#[derive(Clone)]
+pub struct SignalFilter {
+    // key -> time the entry expires
+    entries: Arc<DashMap<String, Instant>>,
+    // puts in the order they expire, as the ttl is fixed, an item whose key was put again
+    // or removed since is skipped
+    order: Arc<Mutex<VecDeque<(String, Instant)>>>,
+    capacity: usize,
+    ttl: Duration,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+}
+
+impl SignalFilter {
+
+    pub fn new(config: Option<&FilterConfig>) -> Self {
+        let capacity = config.and_then(|c| c.capacity).unwrap_or(DEFAULT_CAPACITY);
+        let ttl = config.and_then(|c| c.ttl).unwrap_or(DEFAULT_TTL);
+        let s = Self {
+            entries: Arc::new(DashMap::new()),
+            order: Arc::new(Mutex::new(VecDeque::new())),
+            capacity,
+            ttl: Duration::from_secs(ttl),
+            hits: Arc::new(AtomicU64::new(0)),
+            misses: Arc::new(AtomicU64::new(0)),
+        };
+        let cloned = s.clone();
+        tokio::spawn(async move {
+            let mut timer = tokio::time::interval(cloned.ttl);
+            loop {
+                timer.tick().await;
+                cloned.purge_expired();
+            }
+        });
+        s
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        let now = Instant::now();
+        let hit = match self.entries.get(key) {
+            None => false,
+            Some(expire_at) => *expire_at > now,
+        };
+        if hit {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            self.entries.remove_if(key, |_, expire_at| *expire_at <= now);
+        }
+        hit
+    }
+
+    pub fn put(&self, key: &str) {
+        let expire_at = Instant::now() + self.ttl;
+        let mut order = self.order.lock().unwrap();
+        self.pop_expired(&mut order);
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(key) {
+            self.evict_oldest(&mut order);
+        }
+        self.entries.insert(key.to_string(), expire_at);
+        order.push_back((key.to_string(), expire_at));
+    }
+
+    pub fn size(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    fn purge_expired(&self) {
+        let mut order = self.order.lock().unwrap();
+        self.pop_expired(&mut order);
+    }
+
+    fn pop_expired(&self, order: &mut VecDeque<(String, Instant)>) {
+        let now = Instant::now();
+        while order.front().map(|(_, expire_at)| *expire_at <= now).unwrap_or(false) {
+            let (key, expire_at) = order.pop_front().unwrap();
+            self.entries.remove_if(&key, |_, current| *current == expire_at);
+        }
+    }
+
+    fn evict_oldest(&self, order: &mut VecDeque<(String, Instant)>) {
+        while let Some((key, expire_at)) = order.pop_front() {
+            if self.entries.remove_if(&key, |_, current| *current == expire_at).is_some() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn full_filter_evicts_the_oldest_put() {
+        let filter = SignalFilter::new(Some(&FilterConfig { capacity: Some(2), ttl: Some(60) }));
+        filter.put("a");
+        filter.put("b");
+        // a was put again, so b is the oldest now
+        filter.put("a");
+        filter.put("c");
+        assert!(filter.contains("a") && filter.contains("c"));
+        assert!(!filter.contains("b"));
+        assert_eq!(filter.size(), 2);
+    }
+}
@@ -1,47 +1,58 @@
 #![deny(unused_imports)]
+use std::net::{IpAddr, SocketAddr};
 use std::str::from_utf8;
-use std::sync::Arc;
 use std::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use axum::body::Body;
-use axum::extract::{Query, State};
+use axum::extract::{ConnectInfo, Query, State};
 use axum::response::{IntoResponse, Response};
-use http::StatusCode;
+use http::{header, HeaderMap, StatusCode};
 use tokio::sync::mpsc;
 use tokio::time::timeout;
 use crate::{AppState};
-use crate::client::Client;
+use crate::client::{Client, WsSender};
 use crate::common::{ApiError, ApiResponse, parse_json, SignalMsg, ValidatedBody};
 use axum::Json as JSON;
 use fastwebsockets::{FragmentCollectorRead, Frame, OpCode, Payload, upgrade, WebSocketError};
-use tklog::{error};
-use crate::hub::Hub;
+use tklog::{error, warn};
+use crate::close_code;
+use crate::config::SecurityMode;
+use crate::hub::{Hub, TenantSlot};
+use crate::jwt::Claims;
+use crate::tenant::{self, Tenant};
+use crate::limiter::{canonical_ip, RateLimits};
 use crate::utils::check_token;
 
+// a client which does not read may never take the close frame
+const CLOSE_FRAME_TIMEOUT: Duration = Duration::from_secs(1);
+
 #[derive(serde::Deserialize, Clone)]
 pub struct SearchParams {
     id: String,
     token: Option<String>,
     hello: Option<String>,
+    // app id of the client when several tenants share this node
+    app: Option<String>,
+    // a polling client of the peer id switching to websocket, not a duplicate login
+    upgrade: Option<bool>,
 }
 
 #[axum::debug_handler]
-pub async fn handle_post(State(mut state): State<AppState>,
+pub async fn handle_post(State(mut state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, headers: HeaderMap,
                      Query(params): Query<SearchParams>, ValidatedBody(payload): ValidatedBody) -> Result<ApiResponse, ApiError> {
     if params.id.is_empty() || params.id.len() < 6 {
         return Err(ApiError::Unauthorised)
     }
-    if !check_sign(&state, &params) {
-        return Err(ApiError::TokenInvalid)
-    }
-    if !check_ratelimit(&state) {
-        return Err(ApiError::InternalServerError)
-    }
+    let ip = client_ip(&state, &headers, addr);
+    check_origin(&state, &headers, ip)?;
+    let tenant = resolve_tenant(&state, &params)?;
+    check_sign(&state, &params, tenant.as_deref())?;
+    check_ratelimit(&state, |limits| limits.check_global(ip))?;
     let is_hello = params.hello.is_some();
-    let id = params.id.as_str();
-    let client = state.hub.get_client(id).await;
-    if client.is_some() && !client.unwrap().is_polling {
-        return Err(ApiError::CONFLICT)
-    }
+    let app = tenant.as_ref().map(|t| t.id.as_ref());
+    let key = tenant::peer_key(app, params.id.as_str());
+    let id = key.as_str();
     if is_hello {
         return Ok(ApiResponse::SignalVersion(state.version_number))
     }
@@ -49,36 +60,65 @@ pub async fn handle_post(State(mut state): State<AppState>,
     if let Ok(data) = serde_json::from_slice::<Vec<SignalMsg>>(payload.as_ref()) {
         for msg in data {
             // println!("{:?}", msg);
-            state.hub.process_message(Arc::new(msg), id).await;
+            if !allow_message(&state, tenant.as_deref(), ip, id) {
+                state.hub.rate_limited(&msg, id, app).await;
+                continue;
+            }
+            state.hub.process_message(Arc::new(msg), id, app).await;
         }
     }
     Ok(ApiResponse::OK)
 }
 
-pub async fn handle_long_polling(mut state: AppState, id: &str) -> Response<Body> {
+pub async fn handle_long_polling(mut state: AppState, params: &SearchParams, ip: IpAddr, tenant: Option<Arc<Tenant>>) -> Response<Body> {
+    // every poll is checked, the queued messages and the entry of the peer id are only for the peer
+    let claims = match check_sign(&state, params, tenant.as_deref()) {
+        Ok(claims) => claims,
+        Err(e) => return e.into_response(),
+    };
+    let app = tenant.as_ref().map(|t| t.id.clone());
+    let key = tenant::peer_key(app.as_deref(), params.id.as_str());
+    let id = key.as_str();
     let (tx, mut rx) = mpsc::channel::<()>(1);
-    let mut client = match state.hub.get_client(id).await  {
-        None => {
-            let cli = Client::new_poll(id, tx);
-            join(cli.clone(), &state.hub).await;
-            cli
-        }
-        Some(mut cli) => {
-            if !cli.is_polling {
-                return handle_error("", StatusCode::CONFLICT).into_response()
-            }
+    let resumed = match state.hub.get_client(id).await {
+        // registered before tenants were enabled by a reload, its key looks like one of the app
+        Some(cli) if !tenant::same_app(app.as_deref(), cli.app.as_deref()) => return ApiError::Forbidden.into_response(),
+        // the peer upgraded to websocket, a poll would take over its connection
+        Some(cli) if cli.upgraded => return ApiError::CONFLICT.into_response(),
+        // a poll while the last one still waits comes from another client of the peer id, it logs in
+        Some(mut cli) if cli.is_polling && !cli.is_waiting() => {
             // println!("cli.msg_queue len {}", cli.msg_queue.lock().unwrap().len());
-            if cli.msg_queue.lock().unwrap().len() > 0 {
+            if !cli.msg_queue.lock().unwrap().is_empty() {
                 let t = cli.get_queued_msgs();
                 return JSON(t).into_response()
             }
-            cli.switch_to_http(tx);
-            join(cli.clone(), &state.hub).await;
+            cli.claims = claims.clone();
+            state.hub.resume_polling(cli, tx.clone())
+        }
+        _ => None,
+    };
+    let mut client = match resumed {
+        Some(cli) => cli,
+        // the peer id is connected by websocket or by another polling client, or not at all, or its entry changed meanwhile
+        None => {
+            // a new client is checked before it becomes visible to other peers
+            let slot = match admit(&state, tenant.as_deref(), ip, id) {
+                Ok(slot) => slot,
+                Err(e) => return e.into_response(),
+            };
+            let mut cli = Client::new_poll(id, tx, state.hub.options());
+            cli.claims = claims;
+            cli.app = app;
+            let logged_in = state.hub.login(cli.clone()).await;
+            drop(slot);
+            if !logged_in {
+                return handle_error("", StatusCode::CONFLICT).into_response()
+            }
             cli
         }
     };
 
-    let result = timeout(Duration::from_secs(60), async {
+    let result = timeout(state.hub.options().polling_timeout, async {
         // println!(" rx await!!!!");
         rx.recv().await;
         // println!(" rx.recv()");
@@ -89,7 +129,12 @@ pub async fn handle_long_polling(mut state: AppState, id: &str) -> Response<Body
 
         // "Task result"
     }).await;
+    let replaced = client.is_replaced();
     state.hub.remove_polling(client).await;
+    // the poll was retired by an upgrade to websocket or kicked by a duplicate login, the client must not poll again
+    if replaced {
+        return ApiError::CONFLICT.into_response()
+    }
     return match result {
         Ok(result) => {
             // println!("Task completed successfully: {:?}", result);
@@ -104,23 +149,34 @@ pub async fn handle_long_polling(mut state: AppState, id: &str) -> Response<Body
     }
 }
 
-async fn handle_socket(mut fut: upgrade::UpgradeFut, mut state: AppState, params: SearchParams) -> Result<(), WebSocketError> {
+async fn handle_socket(fut: upgrade::UpgradeFut, state: AppState, ip: IpAddr, tenant: Option<Arc<Tenant>>,
+                       params: SearchParams, claims: Option<Arc<Claims>>, slot: Option<TenantSlot>) -> Result<(), WebSocketError> {
     let ws = fut.await?;
-    let (sender_tx, mut sender_rx) = mpsc::unbounded_channel();
+    let peer_id = tenant::peer_key(tenant.as_ref().map(|t| t.id.as_ref()), params.id.as_str());
+    let (sender_tx, mut sender_rx) = WsSender::channel(state.hub.options());
     let version = state.version_number;
-    let peer_id = params.id.clone();
-    let client = Client::new(&peer_id, sender_tx.clone());
+    let writer = sender_tx.clone();
+    let mut client = Client::new(&peer_id, sender_tx.clone(), state.hub.options());
+    client.claims = claims;
+    client.app = tenant.as_ref().map(|t| t.id.clone());
+    let key = peer_id.clone();
     let mut hub = state.hub.clone();
-    join(client.clone(), &state.hub).await;
+    let limits = state.clone();
+    let generation = client.generation;
     let (rx, mut tx) = ws.split(tokio::io::split);
-    let mut rx = FragmentCollectorRead::new(rx);
-    if !check_sign(&state, &params) {
-        return tx.write_frame(Frame::close(4000, "token is not valid".as_bytes())).await
-    }
-    if !check_ratelimit(&state) {
-        return tx.write_frame(Frame::close(5000, "reach ratelimit".as_bytes())).await
+    let logged_in = if params.upgrade.unwrap_or(false) {
+        state.hub.upgrade(client.clone()).await
+    } else {
+        state.hub.login(client.clone()).await
+    };
+    drop(slot);
+    if !logged_in {
+        let code = close_code::DUPLICATE_LOGIN;
+        let _ = timeout(CLOSE_FRAME_TIMEOUT, tx.write_frame(Frame::close(code, close_code::reason(code).as_bytes()))).await;
+        return Ok(())
     }
-    tokio::task::spawn(async move {
+    let mut rx = FragmentCollectorRead::new(rx);
+    let reader = tokio::task::spawn(async move {
         loop {
             // Empty send_fn is fine because the benchmark does not create obligated writes.
             let frame = match rx
@@ -134,20 +190,22 @@ async fn handle_socket(mut fut: upgrade::UpgradeFut, mut state: AppState, params
             match frame.opcode {
                 OpCode::Close => break,
                 OpCode::Ping => {
-                    match hub.get_client(params.id.as_str()).await  {
-                        None => {}
-                        Some(mut cli) => {
-                            cli.update_ts();
-                        }
-                    };
+                    hub.touch(key.as_str());
                 }
                 OpCode::Text => {
+                    // over the limit messages are dropped, the connection stays open
+                    if !allow_message(&limits, tenant.as_deref(), ip, key.as_str()) {
+                        if let Some(parsed) = from_utf8(frame.payload.as_ref()).ok().and_then(|text| parse_json(text).ok()) {
+                            hub.rate_limited(&parsed, key.as_str(), tenant.as_ref().map(|t| t.id.as_ref())).await;
+                        }
+                        continue;
+                    }
                     let byte_slice = frame.payload.as_ref();
                     match from_utf8(byte_slice) {
                         Ok(text) => {
                             match parse_json(text) {
                                 Ok(parsed) => {
-                                    hub.process_message(Arc::new(parsed), params.id.as_str()).await;
+                                    hub.process_message(Arc::new(parsed), key.as_str(), tenant.as_ref().map(|t| t.id.as_ref())).await;
                                 }
                                 Err(err) => {
                                     // match err.downcast_ref::<ParseJsonError>() {
@@ -176,7 +234,7 @@ async fn handle_socket(mut fut: upgrade::UpgradeFut, mut state: AppState, params
                 _ => {}
             }
         }
-        let _ = sender_tx.send("".to_string());
+        sender_tx.close();
     });
     let msg = &SignalMsg {
         action: Some("ver".to_string()),
@@ -184,48 +242,84 @@ async fn handle_socket(mut fut: upgrade::UpgradeFut, mut state: AppState, params
         ..SignalMsg::default()
     };
     if tx.write_frame(Frame::text(Payload::Owned(serde_json::to_vec(msg).unwrap()))).await.is_ok() {
-        while let Some(msg) = sender_rx.recv().await {
-            if msg == "" {
-                break
-            }
-            if tx.write_frame(Frame::text(Payload::Owned(msg.into_bytes()))).await.is_err() {
-                break
+        loop {
+            tokio::select! {
+                msg = sender_rx.recv() => {
+                    let msg = match msg {
+                        None => break,
+                        Some(msg) => msg,
+                    };
+                    writer.consumed(msg.len());
+                    tokio::select! {
+                        res = tx.write_frame(Frame::text(Payload::Owned(msg.into_bytes()))) => {
+                            if res.is_err() {
+                                break
+                            }
+                        }
+                        // stalled in the middle of a frame, no close frame can follow it
+                        _ = writer.evicting() => break,
+                    }
+                }
+                _ = writer.evicting() => {
+                    let code = close_code::SLOW_CONSUMER;
+                    let _ = timeout(CLOSE_FRAME_TIMEOUT, tx.write_frame(Frame::close(code, close_code::reason(code).as_bytes()))).await;
+                    break
+                }
+                _ = writer.closing() => {
+                    if let Some(code) = writer.close_code() {
+                        let _ = timeout(CLOSE_FRAME_TIMEOUT, tx.write_frame(Frame::close(code, close_code::reason(code).as_bytes()))).await;
+                    }
+                    break
+                }
             }
         }
     }
-    leave(peer_id.as_str(), &state.hub).await;
+    // the connection is over, messages still read from it must not be routed
+    reader.abort();
+    leave(peer_id.as_str(), generation, &state.hub).await;
     Ok(())
 }
 
 pub async fn handle_http_or_websocket(
     ws: Option<upgrade::IncomingUpgrade>,
     State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Query(params): Query<SearchParams>
 ) -> impl IntoResponse {
     if params.id.is_empty() || params.id.len() < 6 {
         return handle_error("id is not valid", StatusCode::UNAUTHORIZED).into_response()
     }
+    let ip = client_ip(&state, &headers, addr);
+    let tenant = match check_origin(&state, &headers, ip).and_then(|_| resolve_tenant(&state, &params)) {
+        Ok(tenant) => tenant,
+        Err(e) => return e.into_response(),
+    };
     if let Some(ws) = ws {
+        let key = tenant::peer_key(tenant.as_ref().map(|t| t.id.as_ref()), params.id.as_str());
+        // rejected before the upgrade, so the client is never registered
+        let (claims, slot) = match check_connect(&state, &params, tenant.as_deref(), ip, key.as_str()) {
+            Ok(checked) => checked,
+            Err(e) => return e.into_response(),
+        };
+        if state.hub.rejects_login(key.as_str(), params.upgrade.unwrap_or(false)) {
+            return ApiError::CONFLICT.into_response()
+        }
         let (response, fut) = ws.upgrade().unwrap();
         tokio::task::spawn(async move {
-            if let Err(e) = tokio::task::unconstrained(handle_socket(fut, state, params)).await {
-                // match e {
-                //     WebSocketError::ConnectionClosed => {}
-                //     _ => {error!("Error in websocket connection", e);}
-                // }
-            }
+            // match e {
+            //     WebSocketError::ConnectionClosed => {}
+            //     _ => {error!("Error in websocket connection", e);}
+            // }
+            let _ = tokio::task::unconstrained(handle_socket(fut, state, ip, tenant, params, claims, slot)).await;
         });
         return response.into_response()
     }
-    handle_long_polling(state, params.id.as_str()).await.into_response()
-}
-
-async fn join(cli: Client, hub: &Hub) {
-    hub.do_register(cli).await;
+    handle_long_polling(state, &params, ip, tenant).await.into_response()
 }
 
-async fn leave(peer_id: &str, hub: &Hub) {
-    hub.do_unregister(peer_id).await;
+async fn leave(peer_id: &str, generation: u64, hub: &Hub) {
+    hub.unregister(peer_id, generation).await;
     // println!("Disconnected {peer_id}");
 }
 
@@ -233,25 +327,321 @@ fn handle_error(msg: &str, code: StatusCode) -> impl IntoResponse {
     (code, format!("{:?}", msg)).into_response()
 }
 
-fn handle_internal_error() -> impl IntoResponse {
-    handle_error("", StatusCode::INTERNAL_SERVER_ERROR)
+// The app of the client, clients of the default namespace have none.
+fn resolve_tenant(state: &AppState, params: &SearchParams) -> Result<Option<Arc<Tenant>>, ApiError> {
+    let tenants = match state.tenants.read().unwrap().clone() {
+        None => return Ok(None),
+        Some(tenants) => tenants,
+    };
+    // keeps keys of the default namespace apart from the keys of apps
+    if params.id.contains(tenant::SEPARATOR) {
+        return Err(ApiError::BadRequest)
+    }
+    match params.app.as_deref() {
+        None if tenants.allow_default() => Ok(None),
+        None => Err(ApiError::Unauthorised),
+        Some(app) => tenants.get(app).map(Some).ok_or(ApiError::Forbidden),
+    }
+}
+
+// Everything a new client has to pass before it is registered.
+fn check_connect(state: &AppState, params: &SearchParams, tenant: Option<&Tenant>, ip: IpAddr, key: &str) -> Result<(Option<Arc<Claims>>, Option<TenantSlot>), ApiError> {
+    let claims = check_sign(state, params, tenant)?;
+    let slot = admit(state, tenant, ip, key)?;
+    Ok((claims, slot))
 }
 
-fn check_sign(state: &AppState, params: &SearchParams) -> bool {
+// The limits of a new client whose token was checked, the client of an app
+// with max clients holds its reserved place until it logged in.
+fn admit(state: &AppState, tenant: Option<&Tenant>, ip: IpAddr, key: &str) -> Result<Option<TenantSlot>, ApiError> {
+    check_ratelimit(state, |limits| limits.check_connect(ip, key))?;
+    if let Some(tenant) = tenant {
+        if let Err(retry_after) = tenant.check_connect() {
+            state.hub.metrics().ratelimit_rejections.fetch_add(1, Ordering::Relaxed);
+            return Err(ApiError::TooManyRequests(retry_after))
+        }
+        if let Some(max_clients) = tenant.max_clients() {
+            return match state.hub.reserve(&tenant.id, max_clients) {
+                Some(slot) => Ok(Some(slot)),
+                None => {
+                    warn!("app", tenant.id, "reached its max clients");
+                    Err(ApiError::ServiceUnavailable)
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+// Over the limit messages are dropped.
+fn allow_message(state: &AppState, tenant: Option<&Tenant>, ip: IpAddr, key: &str) -> bool {
+    if check_ratelimit(state, |limits| limits.check_message(ip, key)).is_err() {
+        return false
+    }
+    match tenant.map(|t| t.check_message()) {
+        Some(Err(_)) => {
+            state.hub.metrics().ratelimit_rejections.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+        _ => true,
+    }
+}
+
+// 401 without a token, 403 with a token which is not valid, the claims of a valid jwt.
+// Clients of an app are checked with the keys of the app only.
+fn check_sign(state: &AppState, params: &SearchParams, tenant: Option<&Tenant>) -> Result<Option<Arc<Claims>>, ApiError> {
     let params = params.clone();
-    if let Some(security) = state.security.clone() {
-        if security.enable && !check_token(params.id.as_str(), params.token, security.token, security.maxTimeStampAge) {
-            return false
+    if let Some(tenant) = tenant {
+        let result = match params.token {
+            None => Err(ApiError::Unauthorised),
+            Some(token) if tenant.check_token(params.id.as_str(), Some(token.clone())) => return Ok(None),
+            Some(_) => Err(ApiError::TokenInvalid),
+        };
+        state.hub.metrics().auth_failures.fetch_add(1, Ordering::Relaxed);
+        return result
+    }
+    let auth = state.auth.read().unwrap().clone();
+    if let Some(security) = auth.security {
+        if security.enable {
+            let token = match params.token {
+                None => {
+                    state.hub.metrics().auth_failures.fetch_add(1, Ordering::Relaxed);
+                    return Err(ApiError::Unauthorised)
+                }
+                Some(token) => token,
+            };
+            let verified = match security.mode {
+                SecurityMode::Token => check_token(params.id.as_str(), Some(token), &security).then_some(None),
+                SecurityMode::Jwt => {
+                    match auth.jwt.map(|v| v.verify(params.id.as_str(), token.as_str())) {
+                        Some(Ok(claims)) => Some(Some(Arc::new(claims))),
+                        Some(Err(e)) => {
+                            warn!("jwt of", params.id, "rejected:", e);
+                            None
+                        }
+                        None => None,
+                    }
+                }
+            };
+            return match verified {
+                Some(claims) => Ok(claims),
+                None => {
+                    state.hub.metrics().auth_failures.fetch_add(1, Ordering::Relaxed);
+                    Err(ApiError::TokenInvalid)
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+// browsers always send the Origin of websocket upgrades and cross-site requests
+fn check_origin(state: &AppState, headers: &HeaderMap, ip: IpAddr) -> Result<(), ApiError> {
+    let policy = state.origin.read().unwrap().clone();
+    if let Some(policy) = policy {
+        let origin = headers.get(header::ORIGIN);
+        let allowed = match origin {
+            None => policy.is_allowed(None),
+            Some(value) => value.to_str().map(|o| policy.is_allowed(Some(o))).unwrap_or(false),
+        };
+        if !allowed {
+            warn!("origin rejected", format!("{:?}", origin), ip);
+            state.hub.metrics().origin_rejections.fetch_add(1, Ordering::Relaxed);
+            return Err(ApiError::Forbidden)
+        }
+    }
+    Ok(())
+}
+
+fn check_ratelimit(state: &AppState, check: impl FnOnce(&RateLimits) -> Result<(), Duration>) -> Result<(), ApiError> {
+    let limits = state.ratelimit.read().unwrap().clone();
+    if let Some(limits) = limits {
+        if let Err(retry_after) = check(&limits) {
+            state.hub.metrics().ratelimit_rejections.fetch_add(1, Ordering::Relaxed);
+            return Err(ApiError::TooManyRequests(retry_after))
         }
     }
-    true
+    Ok(())
 }
 
-fn check_ratelimit(state: &AppState) -> bool {
-    if let Some(limiter) = state.ratelimit.clone() {
-        if limiter.try_wait().is_err() {
-            return false
+// The peer address, or the client address a trusted proxy put into the real ip header.
+fn client_ip(state: &AppState, headers: &HeaderMap, addr: SocketAddr) -> IpAddr {
+    let limits = state.ratelimit.read().unwrap().clone();
+    match limits {
+        None => canonical_ip(addr.ip()),
+        Some(limits) => {
+            let header = limits.real_ip_header()
+                .and_then(|name| headers.get(name))
+                .and_then(|value| value.to_str().ok());
+            limits.real_ip(addr.ip(), header)
         }
     }
-    return true
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::Router;
+    use axum::routing::get;
+    use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpSocket;
+    use crate::config::{Connection, DuplicateLogin, Ratelimit, Security};
+    use crate::filter::SignalFilter;
+    use crate::testing::{app_state, new_hub, polling_client, PEER};
+    use crate::Auth;
+    use super::*;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    fn params(id: &str, token: Option<&str>) -> SearchParams {
+        SearchParams { id: id.to_string(), token: token.map(str::to_string), hello: None, app: None, upgrade: None }
+    }
+
+    fn secured(hub: Hub) -> AppState {
+        let state = app_state(hub);
+        *state.auth.write().unwrap() = Auth {
+            security: Some(Security {
+                enable: true,
+                mode: SecurityMode::Token,
+                maxTimeStampAge: 60,
+                token: Some("secret".to_string()),
+                keys: None,
+                jwt: None,
+            }),
+            jwt: None,
+        };
+        state
+    }
+
+    async fn serve(state: AppState) -> SocketAddr {
+        let app = Router::new().route("/", get(handle_http_or_websocket).post(handle_post).with_state(state));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+        });
+        addr
+    }
+
+    // A websocket of PEER which reads nothing after the upgrade.
+    async fn stalled_websocket(addr: SocketAddr) -> tokio::net::TcpStream {
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.set_recv_buffer_size(4096).unwrap();
+        let mut stream = socket.connect(addr).await.unwrap();
+        let request = format!("GET /?id={} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n", PEER);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte).await.unwrap();
+            response.push(byte[0]);
+        }
+        assert!(response.starts_with(b"HTTP/1.1 101"));
+        stream
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn slow_consumers_are_evicted_and_disconnected() {
+        let options = Connection { ws_queue_size: Some(4), slow_consumer_timeout: Some(0), ..Connection::default() }.options().unwrap();
+        let mut hub = Hub::new(options, SignalFilter::new(None), None, None);
+        let addr = serve(app_state(hub.clone())).await;
+        let mut stream = stalled_websocket(addr).await;
+        while !hub.has_ws_client(PEER) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let msg = Arc::new(SignalMsg {
+            action: Some("signal".to_string()),
+            to_peer_id: Some(PEER.to_string()),
+            data: Some(json!({"sdp": "x".repeat(16 * 1024)})),
+            ..SignalMsg::default()
+        });
+        for _ in 0..10_000 {
+            if hub.counters().slow_consumer_evictions.load(Ordering::Relaxed) > 0 {
+                break;
+            }
+            hub.process_message(msg.clone(), "sender01", None).await;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(hub.counters().slow_consumer_evictions.load(Ordering::Relaxed), 1);
+        assert!(hub.get_client(PEER).await.is_none());
+        // both halves of the socket are gone, the client reads what was sent and then the end
+        let mut buf = vec![0u8; 64 * 1024];
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while stream.read(&mut buf).await.unwrap() > 0 {}
+        }).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn polls_without_a_valid_token_get_nothing_of_the_peer() {
+        let mut hub = new_hub();
+        let poll = polling_client(&hub);
+        assert!(hub.login(poll.clone()).await);
+        poll.msg_queue.lock().unwrap().push(Arc::new(SignalMsg { action: Some("signal".to_string()), ..SignalMsg::default() }));
+        let state = secured(hub.clone());
+        let response = handle_long_polling(state.clone(), &params(PEER, None), LOCALHOST, None).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = handle_long_polling(state, &params(PEER, Some("00000000-1")), LOCALHOST, None).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        // the queue is still there for the peer, and its entry was not taken over
+        assert_eq!(poll.msg_queue.lock().unwrap().len(), 1);
+        assert_eq!(hub.get_client(PEER).await.unwrap().generation, poll.generation);
+        assert_eq!(hub.metrics().auth_failures.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn connects_over_the_limit_are_told_when_to_retry() {
+        let options = Connection { polling_timeout: Some(1), ..Connection::default() }.options().unwrap();
+        let state = app_state(Hub::new(options, SignalFilter::new(None), None, None));
+        *state.ratelimit.write().unwrap() = RateLimits::from_config(Some(&Ratelimit {
+            enable: true,
+            max_rate: Some(1),
+            per_ip: None,
+            per_peer: None,
+            allowlist: None,
+            real_ip_header: None,
+            trusted_proxies: None,
+        }));
+        let cloned = state.clone();
+        let first = tokio::spawn(async move { handle_long_polling(cloned, &params(PEER, None), LOCALHOST, None).await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let response = handle_long_polling(state.clone(), &params("peer000002", None), LOCALHOST, None).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+        assert_eq!(state.hub.metrics().ratelimit_rejections.load(Ordering::Relaxed), 1);
+        assert_eq!(first.await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn second_polling_client_follows_the_duplicate_login_policy() {
+        for policy in [DuplicateLogin::Reject, DuplicateLogin::Kick, DuplicateLogin::Allow] {
+            let options = Connection { polling_timeout: Some(1), duplicate_login: Some(policy), ..Connection::default() }.options().unwrap();
+            let mut hub = Hub::new(options, SignalFilter::new(None), None, None);
+            let poll = |state: AppState| tokio::spawn(async move { handle_long_polling(state, &params(PEER, None), LOCALHOST, None).await });
+            let first = poll(app_state(hub.clone()));
+            while !hub.get_client(PEER).await.is_some_and(|client| client.is_waiting()) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            // the first poll still waits, this one does not resume its entry
+            let second = poll(app_state(hub.clone()));
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            hub.process_message(Arc::new(SignalMsg {
+                action: Some("signal".to_string()),
+                to_peer_id: Some(PEER.to_string()),
+                ..SignalMsg::default()
+            }), "peer000002", None).await;
+            let mut results = vec![];
+            for response in [first.await.unwrap(), second.await.unwrap()] {
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                results.push((status, String::from_utf8_lossy(&body).contains("peer000002")));
+            }
+            let expected = match policy {
+                DuplicateLogin::Reject => [(StatusCode::OK, true), (StatusCode::CONFLICT, false)],
+                DuplicateLogin::Kick => [(StatusCode::CONFLICT, false), (StatusCode::OK, true)],
+                DuplicateLogin::Allow => [(StatusCode::OK, true), (StatusCode::OK, true)],
+            };
+            assert_eq!(results, expected, "{:?}", policy);
+        }
+    }
+}
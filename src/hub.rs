@@ -1,40 +1,138 @@
 #![deny(unused_imports)]
-use std::sync::{Arc, Mutex};
-// use dashmap::DashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
 use tklog::{ warn};
+use tokio::sync::mpsc::Sender;
 use tokio::time::{interval_at, Duration, Instant};
-use crate::client::Client;
-use lru::LruCache;
-use std::num::NonZeroUsize;
-use std::collections::HashMap;
+use crate::client::{self, Client, SendResult};
 use serde_json::Value;
-use crate::common::SignalMsg;
+use crate::common::{Delivery, SignalMsg};
+use crate::cluster::Cluster;
+use crate::filter::SignalFilter;
+use crate::config::{ConnectionOptions, DuplicateLogin, OverflowPolicy};
+use crate::metrics::Metrics;
+use crate::offline::{Hold, OfflineBuffer};
+use crate::tenant;
+
+// number of peers checked by the expiry sweep before it yields to other tasks
+const SWEEP_BATCH_SIZE: usize = 1000;
+
+#[derive(Default)]
+pub struct Counters {
+    // messages discarded because a polling queue was full
+    pub polling_dropped: AtomicU64,
+    // messages discarded because a websocket send buffer was full
+    pub ws_dropped: AtomicU64,
+    // websocket clients closed for staying over their send buffer limit
+    pub slow_consumer_evictions: AtomicU64,
+    // polling clients which switched to websocket under the same peer id
+    pub upgrades: AtomicU64,
+    // signals held for a peer which just disconnected
+    pub offline_held: AtomicU64,
+    // held signals delivered after the peer connected again
+    pub offline_delivered: AtomicU64,
+}
+
+/// Usage of one app on this node.
+#[derive(Default)]
+pub struct TenantStats {
+    // includes the places reserved by clients which are logging in
+    pub clients: AtomicUsize,
+    // messages received for routing from clients of the app
+    pub messages: AtomicU64,
+}
+
+/// A place reserved among the max clients of an app, given back when dropped.
+/// A client which logged in meanwhile is counted by the hub itself.
+pub struct TenantSlot {
+    stats: Arc<TenantStats>,
+}
+
+impl Drop for TenantSlot {
+    fn drop(&mut self) {
+        self.stats.clients.fetch_sub(1, Ordering::Relaxed);
+    }
+}
 
 #[derive(Clone)]
 pub struct Hub {
-    // map: Arc<DashMap<String, Client>>,
-    map: Arc<Mutex<HashMap<String, Client>>>,
-    filter: LruCache<String, ()>,
+    map: Arc<DashMap<String, Client>>,
+    // older connections of peer ids which logged in again under DuplicateLogin::Allow,
+    // locked after the entry of the peer id in map
+    duplicates: Arc<DashMap<String, Vec<Client>>>,
+    filter: SignalFilter,
+    offline: Option<OfflineBuffer>,
+    cluster: Option<Cluster>,
+    options: ConnectionOptions,
+    counters: Arc<Counters>,
+    metrics: Arc<Metrics>,
+    tenants: Arc<DashMap<Arc<str>, Arc<TenantStats>>>,
+    shutting_down: Arc<AtomicBool>,
 }
 
 impl Hub {
 
-    pub fn new() -> Self {
+    pub fn new(options: ConnectionOptions, filter: SignalFilter, offline: Option<OfflineBuffer>, cluster: Option<Cluster>) -> Self {
         let s = Self {
-            // map: Arc::new(DashMap::new()),
-            map: Arc::new(Mutex::new(HashMap::new())),
-            filter: LruCache::new(NonZeroUsize::new(6000).unwrap()),
+            map: Arc::new(DashMap::new()),
+            duplicates: Arc::new(DashMap::new()),
+            filter,
+            offline,
+            cluster,
+            options,
+            counters: Arc::new(Counters::default()),
+            metrics: Arc::new(Metrics::default()),
+            tenants: Arc::new(DashMap::new()),
+            shutting_down: Arc::new(AtomicBool::new(false)),
         };
         let cloned = s.clone();
         tokio::spawn(async move {
             cloned.check_conns().await;
         });
+        if let Some(offline) = s.offline.clone() {
+            let cloned = s.clone();
+            tokio::spawn(async move {
+                cloned.check_offline(offline).await;
+            });
+        }
         s
     }
 
+    // Peers which did not come back in time are reported as not found to the senders of their held signals.
+    async fn check_offline(mut self, offline: OfflineBuffer) {
+        let mut timer = interval_at(Instant::now() + Duration::from_secs(1), Duration::from_secs(1));
+        loop {
+            timer.tick().await;
+            for (to_peer_id, app, msgs) in offline.take_expired() {
+                self.report_not_found(to_peer_id.as_str(), app.as_deref(), msgs).await;
+            }
+        }
+    }
+
+    // Tell the senders of held signals that the peer did not get them.
+    async fn report_not_found(&mut self, to_peer_id: &str, app: Option<&str>, msgs: Vec<Arc<SignalMsg>>) {
+        let mut senders: Vec<String> = msgs.iter().filter_map(|msg| msg.from_peer_id.clone()).collect();
+        senders.sort();
+        senders.dedup();
+        for peer_id in senders {
+            let key = key_for_filter(peer_id.as_str(), to_peer_id);
+            self.handle_peer_not_found(peer_id.as_str(), to_peer_id, key.as_str(), app).await;
+        }
+        // these were acked as queued, the senders learn they were never delivered
+        for msg in msgs.iter() {
+            if let Some(peer_id) = msg.from_peer_id.as_deref() {
+                self.ack(msg, peer_id, to_peer_id, app, vec![Delivery::PeerNotFound; batch_len(msg)]).await;
+            }
+        }
+    }
+
+    // Expired clients are removed in small batches, each holding a shard lock only briefly,
+    // so message routing goes on while the sweep runs.
     async fn check_conns(&self) {
-        let start = Instant::now() + Duration::from_secs(6*60);
-        let mut timer = interval_at(start, Duration::from_secs(6*60));
+        let start = Instant::now() + self.options.check_interval;
+        let mut timer = interval_at(start, self.options.check_interval);
         loop {
             timer.tick().await;
             warn!("定时任务执行时间:", format!("{:?}", Instant::now()));
@@ -43,26 +141,32 @@ impl Hub {
             let mut http_count_removed = 0;
             let mut ws_count = 0;
             let mut http_count = 0;
-            let mut clients_to_remove = Vec::new();
-            for (_, client) in self.map.lock().unwrap().iter() {
-                if client.is_expired(now) {
-                    clients_to_remove.push(client.clone());
-                    if client.is_polling {
-                        http_count_removed += 1;
-                    } else {
-                        ws_count_removed += 1;
+            let peer_ids = self.local_peer_ids().await;
+            for batch in peer_ids.chunks(SWEEP_BATCH_SIZE) {
+                for peer_id in batch {
+                    // duplicates first, an expired primary connection is then replaced by one which is alive
+                    let mut expired = self.expire_duplicates(peer_id, now);
+                    let generation = self.map.get(peer_id).filter(|client| client.is_expired(now)).map(|client| client.generation);
+                    if let Some(generation) = generation {
+                        expired.extend(self.remove(peer_id, generation).await);
                     }
-                } else {
-                    if client.is_polling {
-                        http_count += 1;
-                    } else {
-                        ws_count += 1;
+                    for mut client in expired {
+                        if client.is_polling {
+                            http_count_removed += 1;
+                        } else {
+                            ws_count_removed += 1;
+                        }
+                        tokio::spawn(async move {
+                            client.close().await;
+                        });
+                    }
+                    match self.map.get(peer_id).map(|client| client.is_polling) {
+                        Some(true) => http_count += 1,
+                        Some(false) => ws_count += 1,
+                        None => {}
                     }
                 }
-            }
-            for cli in &clients_to_remove {
-                cli.clone().close().await;
-                self.map.lock().unwrap().remove(&cli.peer_id);
+                tokio::task::yield_now().await;
             }
             if ws_count_removed > 0 || http_count_removed > 0 {
                 warn!("check cmap finished, closed clients: ws", ws_count_removed, "polling", http_count_removed)
@@ -71,26 +175,311 @@ impl Hub {
         }
     }
 
-    pub async fn do_register(&self, client: Client) {
-        // println!("{} do_register", client.peer_id);
-        // self.map.lock().insert(client.peer_id.clone(), client);
-        self.map.lock().unwrap().insert(client.peer_id.clone(), client);
+    /// Register a new connection, applying the duplicate login policy when its peer id is connected.
+    /// Returns false when the connection was rejected.
+    pub async fn login(&self, client: Client) -> bool {
+        let peer_id = client.peer_id.clone();
+        let app = client.app.clone();
+        let replaced = match self.map.entry(peer_id.clone()) {
+            // a long poll must not take over the websocket the peer upgraded to
+            Entry::Occupied(entry) if client.is_polling && entry.get().upgraded => return false,
+            Entry::Occupied(_) if self.options.duplicate_login == DuplicateLogin::Reject => return false,
+            Entry::Occupied(mut entry) if self.options.duplicate_login == DuplicateLogin::Allow => {
+                let old = entry.insert(client);
+                self.duplicates.entry(peer_id.clone()).or_default().push(old);
+                None
+            }
+            Entry::Occupied(mut entry) => Some(entry.insert(client)),
+            Entry::Vacant(entry) => {
+                entry.insert(client);
+                None
+            }
+        };
+        if let Some(old) = replaced {
+            // the peer is still connected, nothing is held for it
+            self.retired(&old);
+            old.kick();
+        }
+        self.joined(app.as_deref());
+        if let Some(cluster) = self.cluster.as_ref() {
+            cluster.announce_join(&peer_id).await;
+        }
+        self.clone().deliver_held(&peer_id).await;
+        true
     }
 
-    pub async fn do_unregister(&self, peer_id: &str) -> bool {
-        self.map.lock().unwrap().remove(peer_id).is_some()
+    /// Switch the polling client of the peer id to the websocket of the client, its queued messages
+    /// are sent over the socket. Without a polling client of the peer id this is a login.
+    pub async fn upgrade(&self, client: Client) -> bool {
+        if let Some(mut entry) = self.map.get_mut(&client.peer_id) {
+            if entry.is_polling {
+                let polling = entry.clone();
+                let dropped = entry.switch_to_ws(client.ws.clone().unwrap());
+                entry.generation = client.generation;
+                entry.claims = client.claims;
+                drop(entry);
+                // the pending long poll returns with 409 and its entry is retired, so it does not come back
+                polling.kick();
+                self.counters.upgrades.fetch_add(1, Ordering::Relaxed);
+                let mut hub = self.clone();
+                for msg in dropped {
+                    self.counters.ws_dropped.fetch_add(1, Ordering::Relaxed);
+                    hub.notify_dropped(client.peer_id.as_str(), &msg, client.app.as_deref()).await;
+                }
+                return true
+            }
+        }
+        self.login(client).await
+    }
+
+    /// Whether the duplicate login policy refuses a new connection of the peer id,
+    /// asked before a websocket upgrade so the client gets a 409.
+    pub fn rejects_login(&self, peer_id: &str, upgrade: bool) -> bool {
+        if self.options.duplicate_login != DuplicateLogin::Reject {
+            return false
+        }
+        self.map.get(peer_id).map(|client| !(upgrade && client.is_polling)).unwrap_or(false)
+    }
+
+    // Send the signals held while the peer was away, if it is connected now. They are sent like
+    // any other signal, a signal the peer has no room for is reported to its sender. When the
+    // peer is gone again the rest is held once more, or reported as not found.
+    async fn deliver_held(&mut self, peer_id: &str) {
+        let offline = match self.offline.clone() {
+            Some(offline) => offline,
+            None => return,
+        };
+        let app = match self.map.get(peer_id) {
+            Some(client) => client.app.clone(),
+            None => return,
+        };
+        let mut delivered = 0;
+        let mut lost = vec![];
+        for msg in offline.take(peer_id) {
+            let sent = match self.get_client(peer_id).await {
+                Some(client) => match self.send_json_to_client(client, msg.clone()).await {
+                    SendResult::Sent => true,
+                    // an older message made room for this one
+                    SendResult::Dropped(dropped) => !Arc::ptr_eq(&dropped, &msg),
+                    // a duplicate connection of the peer which is left got it
+                    SendResult::Evicted | SendResult::Failed if self.map.contains_key(peer_id) => true,
+                    SendResult::Evicted | SendResult::Failed => false,
+                },
+                None => false,
+            };
+            if sent {
+                delivered += 1;
+            } else if self.map.get(peer_id).is_none() && !matches!(offline.hold(peer_id, msg.clone(), |_| true), Hold::Held) {
+                lost.push(msg);
+            }
+        }
+        self.counters.offline_delivered.fetch_add(delivered, Ordering::Relaxed);
+        if !lost.is_empty() {
+            self.report_not_found(peer_id, app.as_deref(), lost).await;
+        }
+    }
+
+    /// Unregister the peer id only while its entry has this generation,
+    /// the cleanup of a connection must not remove the connection which replaced it.
+    pub async fn unregister(&self, peer_id: &str, generation: u64) -> bool {
+        self.remove(peer_id, generation).await.is_some()
+    }
+
+    // Unregister the connection with this generation, returns it if it was registered.
+    async fn remove(&self, peer_id: &str, generation: u64) -> Option<Client> {
+        let (client, last) = self.detach(peer_id, generation)?;
+        if last {
+            self.unregistered(peer_id, Some((peer_id.to_string(), client.clone()))).await;
+        } else {
+            // another connection of the peer id is still registered
+            self.retired(&client);
+        }
+        Some(client)
+    }
+
+    // Take the connection with this generation out of the map or out of the duplicates,
+    // the latest duplicate takes the place of a primary connection.
+    // Returns the connection and whether it was the last one of the peer id.
+    fn detach(&self, peer_id: &str, generation: u64) -> Option<(Client, bool)> {
+        let mut entry = match self.map.entry(peer_id.to_string()) {
+            Entry::Occupied(entry) => entry,
+            Entry::Vacant(_) => return None,
+        };
+        let mut duplicates = match self.duplicates.entry(peer_id.to_string()) {
+            Entry::Occupied(duplicates) => duplicates,
+            Entry::Vacant(_) if entry.get().generation == generation => return Some((entry.remove(), true)),
+            Entry::Vacant(_) => return None,
+        };
+        let detached = if entry.get().generation == generation {
+            // never empty, an emptied list is removed
+            let next = duplicates.get_mut().pop().unwrap();
+            entry.insert(next)
+        } else {
+            let position = duplicates.get().iter().position(|client| client.generation == generation)?;
+            duplicates.get_mut().remove(position)
+        };
+        if duplicates.get().is_empty() {
+            duplicates.remove();
+        }
+        Some((detached, false))
+    }
+
+    // Unregister the duplicates of the peer id which expired, returns them.
+    fn expire_duplicates(&self, peer_id: &str, now: Instant) -> Vec<Client> {
+        let expired: Vec<u64> = match self.duplicates.get(peer_id) {
+            Some(duplicates) => duplicates.iter().filter(|client| client.is_expired(now)).map(|client| client.generation).collect(),
+            None => return vec![],
+        };
+        let expired: Vec<Client> = expired.into_iter()
+            .filter_map(|generation| self.detach(peer_id, generation))
+            .map(|(client, _)| client)
+            .collect();
+        for client in expired.iter() {
+            self.retired(client);
+        }
+        expired
+    }
+
+    /// Start the next poll of a registered polling client, None when its entry changed
+    /// since it was read, e.g. because the peer id logged in again.
+    pub fn resume_polling(&self, mut client: Client, http: Sender<()>) -> Option<Client> {
+        let generation = client.generation;
+        client.switch_to_http(http);
+        client.update_ts();
+        client.generation = client::next_generation();
+        match self.map.get_mut(&client.peer_id) {
+            Some(mut entry) if entry.generation == generation => {
+                *entry = client.clone();
+                Some(client)
+            }
+            _ => None,
+        }
+    }
+
+    async fn unregistered(&self, peer_id: &str, removed: Option<(String, Client)>) -> bool {
+        if let Some((_, client)) = removed.as_ref() {
+            self.removed(client);
+            if let Some(cluster) = self.cluster.as_ref() {
+                cluster.announce_leave(peer_id).await;
+            }
+        }
+        removed.is_some()
+    }
+
+    fn joined(&self, app: Option<&str>) {
+        if let Some(app) = app {
+            self.tenant(app).clients.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn removed(&self, client: &Client) {
+        self.retired(client);
+        // clients leaving a node which shuts down come back to another node
+        if let Some(offline) = self.offline.as_ref() {
+            if !self.shutting_down.load(Ordering::Relaxed) {
+                offline.left(&client.peer_id, client.app.clone());
+            }
+        }
+    }
+
+    // A client whose entry is gone, either removed or replaced by a new connection of the peer.
+    fn retired(&self, client: &Client) {
+        if let Some(app) = client.app.as_ref() {
+            self.tenant(app).clients.fetch_sub(1, Ordering::Relaxed);
+        }
+        let lifetime = client.created.elapsed();
+        if client.is_polling {
+            self.metrics.polling_lifetime.observe(lifetime);
+        } else {
+            self.metrics.ws_lifetime.observe(lifetime);
+        }
+    }
+
+    fn tenant(&self, app: &str) -> Arc<TenantStats> {
+        if let Some(stats) = self.tenants.get(app) {
+            return stats.clone()
+        }
+        self.tenants.entry(Arc::from(app)).or_default().clone()
+    }
+
+    /// Reserve a place for a new client of the app, None when it has max clients on this node.
+    pub fn reserve(&self, app: &str, max_clients: usize) -> Option<TenantSlot> {
+        let stats = self.tenant(app);
+        stats.clients.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |clients| (clients < max_clients).then_some(clients + 1)).ok()?;
+        Some(TenantSlot { stats })
+    }
+
+    /// Usage of every app which had clients on this node, ordered by app id.
+    pub fn tenant_stats(&self) -> Vec<(Arc<str>, Arc<TenantStats>)> {
+        let mut stats: Vec<_> = self.tenants.iter().map(|entry| (entry.key().clone(), entry.value().clone())).collect();
+        stats.sort_by(|a, b| a.0.cmp(&b.0));
+        stats
+    }
+
+    pub async fn local_peer_ids(&self) -> Vec<String> {
+        self.map.iter().map(|entry| entry.key().clone()).collect()
+    }
+
+    pub async fn num_remote_peers(&self) -> Option<usize> {
+        match self.cluster.as_ref() {
+            None => None,
+            Some(cluster) => Some(cluster.num_remote_peers().await),
+        }
+    }
+
+    pub fn options(&self) -> ConnectionOptions {
+        self.options
+    }
+
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn filter(&self) -> &SignalFilter {
+        &self.filter
+    }
+
+    pub fn offline(&self) -> Option<&OfflineBuffer> {
+        self.offline.as_ref()
     }
 
     pub async fn num_client(&self) -> usize {
-        self.map.lock().unwrap().len()
+        self.map.len()
+    }
+
+    /// Number of websocket and of polling clients.
+    pub fn num_clients_by_kind(&self) -> (usize, usize) {
+        let polling = self.map.iter().filter(|entry| entry.value().is_polling).count();
+        (self.map.len().saturating_sub(polling), polling)
     }
 
-    pub async fn has_client(&self, peer_id: &str) -> bool {
-        self.map.lock().unwrap().contains_key(peer_id)
+    /// Connected peers which lost the most messages to a full polling queue, most first.
+    pub fn dropped_by_peer(&self, limit: usize) -> Vec<(String, u64)> {
+        let mut dropped: Vec<(String, u64)> = self.map.iter()
+            .map(|entry| (entry.key().clone(), entry.value().dropped.load(Ordering::Relaxed)))
+            .filter(|(_, dropped)| *dropped > 0)
+            .collect();
+        dropped.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        dropped.truncate(limit);
+        dropped
     }
 
-    pub async fn process_message(&mut self, mut msg: Arc<SignalMsg>, peer_id: &str) {
+    /// Whether the peer id is connected by websocket, a polling client may upgrade.
+    pub fn has_ws_client(&self, peer_id: &str) -> bool {
+        self.map.get(peer_id).map(|client| !client.is_polling).unwrap_or(false)
+    }
+
+    /// Route a message of the client with this key, peers are looked up in the namespace of its app.
+    pub async fn process_message(&mut self, msg: Arc<SignalMsg>, peer_id: &str, app: Option<&str>) {
+        if let Some(app) = app {
+            self.tenant(app).messages.fetch_add(1, Ordering::Relaxed);
+        }
         if let Some(action) = &msg.action {
+            self.metrics.routed(action);
             match action.as_ref() {
                 "ping" | "heartbeat" => {
                     self.process_ping(peer_id).await;
@@ -98,29 +487,41 @@ impl Hub {
                 action => {
                     let to_peer_id = match msg.to_peer_id() {
                         None => { return; }
-                        Some(to) => to
+                        Some(to) => tenant::peer_key(app, to)
                     };
+                    let to_peer_id = to_peer_id.as_str();
                     let msg = Arc::new(SignalMsg{
                         action: msg.action.clone(),
                         from_peer_id: Some(peer_id.to_string()),
                         data: msg.data.clone(),
+                        // a node the message is forwarded to sends the ack
+                        id: msg.id.clone(),
+                        ack: msg.ack,
                         ..SignalMsg::default()
                     });
                     let key = key_for_filter(peer_id, to_peer_id);
                     if self.filter.contains(&key) {
+                        self.ack(&msg, peer_id, to_peer_id, app, vec![Delivery::Filtered; batch_len(&msg)]).await;
                         return;
                     }
                     // println!("from {} to {}", peer_id, to_peer_id.as_str());
-                    let target = self.get_client(to_peer_id).await;
+                    let target = self.get_client(to_peer_id).await
+                        .filter(|target| tenant::same_app(app, target.app.as_deref()));
+                    // the peer may be connected to another node in the cluster
+                    if target.is_none() && self.forward(to_peer_id, &msg, app).await {
+                        return;
+                    }
                     // if target.is_none() {
                     //     println!("target.is_none");
                     // }
                     match action {
                         "signal" => {
-                            self.process_signal(target, msg, to_peer_id, peer_id, key.as_str()).await;
+                            let status = self.process_signal(target, msg.clone(), to_peer_id, peer_id, key.as_str(), app).await;
+                            self.ack(&msg, peer_id, to_peer_id, app, vec![status]).await;
                         }
                         "signals" => {
-                            self.process_signals(target, msg, to_peer_id, peer_id, key.as_str()).await;
+                            let results = self.process_signals(target, msg.clone(), to_peer_id, peer_id, key.as_str(), app).await;
+                            self.ack(&msg, peer_id, to_peer_id, app, results).await;
                         }
                         "reject" => {
                             self.process_reject(target, msg, key.as_str()).await;
@@ -132,14 +533,14 @@ impl Hub {
                 }
             }
         } else {
-            if let Some(mut peer) = self.get_client(peer_id).await {
-                peer.update_ts();
-            }
+            self.touch(peer_id);
         }
 
     }
 
-    async fn process_signals(&mut self, target: Option<Client>, msg: Arc<SignalMsg>, to_peer_id: &str, peer_id: &str, key: &str) {
+    // Returns the outcome of every item, items after the first one which was not sent share its outcome.
+    async fn process_signals(&mut self, target: Option<Client>, msg: Arc<SignalMsg>, to_peer_id: &str, peer_id: &str, key: &str, app: Option<&str>) -> Vec<Delivery> {
+        let mut results = vec![];
         if let Some(data) = msg.data.as_ref() {
             match data {
                 Value::Array(array) => {
@@ -150,8 +551,11 @@ impl Hub {
                             data: Some(item.to_owned()),
                             ..SignalMsg::default()
                         };
-                        if !self.process_signal(target.clone(), Arc::new(json), to_peer_id, peer_id, key).await {
-                            return;
+                        let status = self.process_signal(target.clone(), Arc::new(json), to_peer_id, peer_id, key, app).await;
+                        results.push(status);
+                        if !matches!(status, Delivery::Delivered | Delivery::Queued) {
+                            results.resize(array.len(), status);
+                            break;
                         }
                     }
                 }
@@ -160,87 +564,904 @@ impl Hub {
                 }
             }
         }
-
+        results
     }
 
-    async fn process_signal(&mut self, target: Option<Client>, msg: Arc<SignalMsg>, to_peer_id: &str, peer_id: &str, key: &str) -> bool {
+    async fn process_signal(&mut self, target: Option<Client>, msg: Arc<SignalMsg>, to_peer_id: &str, peer_id: &str, key: &str, app: Option<&str>) -> Delivery {
         if let Some(target) = target {
-            let success = self.send_json_to_client(target, msg).await;
-            if !success {
-                // println!("send_json_to_client not success");
-                let peer = self.get_client(peer_id).await;
-                self.handle_peer_not_found(peer, to_peer_id, key).await;
+            let polling = target.is_polling;
+            return match self.send_json_to_client(target, msg.clone()).await {
+                SendResult::Sent if polling => Delivery::Queued,
+                SendResult::Sent => Delivery::Delivered,
+                // the sender has been notified, a batch only goes on if an older message made room for this one
+                SendResult::Dropped(dropped) if !Arc::ptr_eq(&dropped, &msg) => Delivery::Queued,
+                SendResult::Dropped(_) => Delivery::Dropped,
+                SendResult::Evicted | SendResult::Failed => {
+                    // println!("send_json_to_client not success");
+                    self.hold_or_not_found(msg, to_peer_id, peer_id, key, app).await
+                }
             }
-            return success;
         }
-        let peer = self.get_client(peer_id).await;
         // println!("handle_peer_not_found {}", peer.clone().unwrap().peer_id);
-        self.handle_peer_not_found(peer, to_peer_id, key).await;
-        return false
+        self.hold_or_not_found(msg, to_peer_id, peer_id, key, app).await
+    }
+
+    // A peer which disconnected moments ago gets its signals when it comes back,
+    // any other missing peer is reported as not found right away.
+    async fn hold_or_not_found(&mut self, msg: Arc<SignalMsg>, to_peer_id: &str, peer_id: &str, key: &str, app: Option<&str>) -> Delivery {
+        if let Some(offline) = self.offline.clone() {
+            match offline.hold(to_peer_id, msg.clone(), |held_app| tenant::same_app(app, held_app)) {
+                Hold::Held => {
+                    self.counters.offline_held.fetch_add(1, Ordering::Relaxed);
+                    // the peer may have connected again meanwhile
+                    self.deliver_held(to_peer_id).await;
+                    return Delivery::Queued
+                }
+                Hold::Full => {
+                    self.notify_dropped(to_peer_id, &msg, app).await;
+                    return Delivery::Dropped
+                }
+                Hold::Unknown => {}
+            }
+        }
+        self.handle_peer_not_found(peer_id, to_peer_id, key, app).await;
+        Delivery::PeerNotFound
+    }
+
+    /// Ack a message which was not routed because its sender is over the rate limit.
+    pub async fn rate_limited(&mut self, msg: &SignalMsg, peer_id: &str, app: Option<&str>) {
+        let to_peer_id = match msg.to_peer_id() {
+            Some(to) => tenant::peer_key(app, to),
+            None => return,
+        };
+        if let Some("signal" | "signals") = msg.action.as_deref() {
+            self.ack(msg, peer_id, to_peer_id.as_str(), app, vec![Delivery::RateLimited; batch_len(msg)]).await;
+        }
+    }
+
+    // Tell the sender what became of its signal or of every item of its batch, if it asked.
+    // The empty signal of peer not found is still sent, it also follows a held signal which expired.
+    async fn ack(&mut self, msg: &SignalMsg, peer_id: &str, to_peer_id: &str, app: Option<&str>, results: Vec<Delivery>) {
+        if !msg.wants_ack() {
+            return;
+        }
+        let batch = msg.action.as_deref() == Some("signals");
+        let ack = SignalMsg {
+            action: Some("ack".to_string()),
+            from_peer_id: Some(to_peer_id.to_string()),
+            id: msg.id.clone(),
+            status: if batch { None } else { results.first().copied() },
+            results: if batch { Some(results) } else { None },
+            ..SignalMsg::default()
+        };
+        match self.get_client(peer_id).await {
+            Some(client) => { self.send_json_to_client(client, Arc::new(ack)).await; }
+            // the sender may be connected to another node in the cluster
+            None => { self.forward(peer_id, &ack, app).await; }
+        }
     }
 
     async fn process_reject(&mut self, target: Option<Client>, msg: Arc<SignalMsg>, key: &str) {
         if let Some(target) = target {
-            self.filter.put(key.to_string(), ());
+            self.filter.put(key);
             self.send_json_to_client(target, msg).await;
         }
     }
 
-    async fn handle_peer_not_found(&mut self, client: Option<Client>, to_peer_id: &str, key: &str) {
-        self.filter.put(key.to_string(), ());
+    async fn handle_peer_not_found(&mut self, peer_id: &str, to_peer_id: &str, key: &str, app: Option<&str>) {
+        self.metrics.peer_not_found.fetch_add(1, Ordering::Relaxed);
+        self.filter.put(key);
         let msg = SignalMsg {
             action: Some("signal".to_string()),
             from_peer_id: Some(to_peer_id.to_string()),
             ..SignalMsg::default()
         };
-        if let Some(client) = client {
+        if let Some(client) = self.get_client(peer_id).await {
             // println!("{} handle_peer_not_found", client.peer_id);
             self.send_json_to_client(client, Arc::new(msg)).await;
+        } else {
+            // the sender may be connected to another node in the cluster
+            self.forward(peer_id, &msg, app).await;
         }
 
     }
 
+    /// Handle a message which another node forwarded to a peer of this node.
+    pub async fn process_forwarded(&mut self, to_peer_id: &str, msg: SignalMsg, app: Option<&str>) {
+        let peer_id = match msg.from_peer_id.clone() {
+            None => { return; }
+            Some(from) => from
+        };
+        let target = self.get_client(to_peer_id).await
+            .filter(|target| tenant::same_app(app, target.app.as_deref()));
+        let action = msg.action.clone().unwrap_or_default();
+        let msg = Arc::new(msg);
+        let key = key_for_filter(peer_id.as_str(), to_peer_id);
+        match action.as_str() {
+            // an empty signal is the peer-not-found notice from another node, never bounce it back
+            "signal" if msg.data.is_none() => {
+                if let Some(target) = target {
+                    self.send_json_to_client(target, msg).await;
+                }
+            }
+            "dropped" | "ack" => {
+                if let Some(target) = target {
+                    self.send_json_to_client(target, msg).await;
+                }
+            }
+            "signal" => {
+                let status = self.process_signal(target, msg.clone(), to_peer_id, peer_id.as_str(), key.as_str(), app).await;
+                self.ack(&msg, peer_id.as_str(), to_peer_id, app, vec![status]).await;
+            }
+            "signals" => {
+                let results = self.process_signals(target, msg.clone(), to_peer_id, peer_id.as_str(), key.as_str(), app).await;
+                self.ack(&msg, peer_id.as_str(), to_peer_id, app, results).await;
+            }
+            "reject" => {
+                self.process_reject(target, msg, key.as_str()).await;
+            }
+            _ => {
+                warn!("unknown forwarded action", action);
+            }
+        }
+    }
+
+    async fn forward(&self, to_peer_id: &str, msg: &SignalMsg, app: Option<&str>) -> bool {
+        match self.cluster.as_ref() {
+            None => false,
+            Some(cluster) => cluster.forward(to_peer_id, msg, app).await,
+        }
+    }
+
     async fn process_ping(&mut self, peer_id: &str) {
-        if let Some(mut peer) = self.get_client(peer_id).await {
-            peer.update_ts();
+        self.touch(peer_id);
+        if let Some(peer) = self.get_client(peer_id).await {
             let msg = SignalMsg {
                 action: Some("pong".to_string()),
                 ..SignalMsg::default()
             };
-            if !peer.send_message(Arc::new(msg)).await {
-                self.do_unregister(peer_id).await;
-            }
+            // a peer id connected more than once cannot tell which connection pinged
+            self.send_json_to_client(peer, Arc::new(msg)).await;
         }
     }
 
     pub async fn get_client(&mut self, peer_id: &str) -> Option<Client> {
-        // match self.map.get_mut(peer_id) {
-        match self.map.lock().unwrap().get(peer_id) {
+        self.map.get(peer_id).map(|value| value.clone())
+    }
+
+    /// Refresh the last active time of the registered clients of the peer id, not of a copy.
+    pub fn touch(&self, peer_id: &str) {
+        if let Some(mut client) = self.map.get_mut(peer_id) {
+            client.update_ts();
+            if let Some(mut duplicates) = self.duplicates.get_mut(peer_id) {
+                duplicates.iter_mut().for_each(Client::update_ts);
+            }
+        }
+    }
+
+    async fn send_json_to_client(&mut self, target: Client, msg: Arc<SignalMsg>) -> SendResult {
+        // sent while the current entry is locked, a copy read before the peer upgraded to
+        // websocket would fill the queue of a poll which is over
+        let (target, result, failed) = match self.map.get(target.peer_id.as_str()) {
+            Some(current) => {
+                let result = current.send(msg.clone());
+                let failed = self.send_to_duplicates(current.key(), &msg);
+                (current.clone(), result, failed)
+            }
             None => {
-                None
+                let result = target.send(msg);
+                (target, result, vec![])
+            }
+        };
+        for generation in failed {
+            self.unregister(target.peer_id.as_str(), generation).await;
+        }
+        match result {
+            SendResult::Sent => {}
+            SendResult::Dropped(ref dropped) => {
+                if target.is_polling {
+                    self.counters.polling_dropped.fetch_add(1, Ordering::Relaxed);
+                    warn!("polling queue of", target.peer_id, "is full, dropped", target.dropped.load(Ordering::Relaxed));
+                } else {
+                    self.counters.ws_dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                self.notify_dropped(target.peer_id.as_str(), dropped, target.app.as_deref()).await;
+            }
+            SendResult::Evicted => {
+                self.counters.slow_consumer_evictions.fetch_add(1, Ordering::Relaxed);
+                warn!("evict slow consumer", target.peer_id);
+                self.unregister(target.peer_id.as_str(), target.generation).await;
             }
-            Some(value) => {
-                Some(value.clone())
+            SendResult::Failed => {
+                // warn!("send msg to", target.peer_id, "error, polling", target.is_polling);
+                self.unregister(target.peer_id.as_str(), target.generation).await;
             }
         }
+        result
     }
 
-    async fn send_json_to_client(&self, mut target: Client, msg: Arc<SignalMsg>) -> bool {
-        if !target.send_message(msg).await {
-            // warn!("send msg to", target.peer_id, "error, polling", target.is_polling);
-            self.do_unregister(target.peer_id.as_str()).await;
-            return false;
+    // Every connection of a peer id which logged in more than once gets the messages of the peer id,
+    // returns the generations of the duplicates which failed. Only the primary connection notifies senders.
+    fn send_to_duplicates(&self, peer_id: &str, msg: &Arc<SignalMsg>) -> Vec<u64> {
+        let duplicates = match self.duplicates.get(peer_id) {
+            Some(duplicates) => duplicates,
+            None => return vec![],
+        };
+        duplicates.iter().filter_map(|client| match client.send(msg.clone()) {
+            SendResult::Sent => None,
+            SendResult::Dropped(_) => {
+                let dropped = if client.is_polling { &self.counters.polling_dropped } else { &self.counters.ws_dropped };
+                dropped.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+            SendResult::Evicted => {
+                self.counters.slow_consumer_evictions.fetch_add(1, Ordering::Relaxed);
+                warn!("evict slow consumer", client.peer_id);
+                Some(client.generation)
+            }
+            SendResult::Failed => Some(client.generation),
+        }).collect()
+    }
+
+    // Tell the sender of a message discarded from a full polling queue.
+    async fn notify_dropped(&mut self, to_peer_id: &str, dropped: &SignalMsg, app: Option<&str>) {
+        let peer_id = match dropped.from_peer_id.as_ref() {
+            // hub notices have no sender, their from_peer_id is the peer they are about
+            Some(_) if dropped.is_notice() => { return; }
+            None => { return; }
+            Some(from) => from
+        };
+        let action = match self.options.polling_overflow {
+            OverflowPolicy::Reject if self.map.get(to_peer_id).map(|c| c.is_polling).unwrap_or(false) => "reject",
+            _ => "dropped",
+        };
+        let notice = SignalMsg {
+            action: Some(action.to_string()),
+            from_peer_id: Some(to_peer_id.to_string()),
+            reason: Some("message queue of peer is full".to_string()),
+            ..SignalMsg::default()
+        };
+        match self.get_client(peer_id).await {
+            // a notice which does not fit into the sender's queue is not reported again
+            Some(mut peer) => { peer.send_message(Arc::new(notice)).await; }
+            None => { self.forward(peer_id, &notice, app).await; }
         }
-        true
     }
 
+    /// End a poll, the client stays registered between two polls unless its entry
+    /// was replaced or removed during the poll.
     pub async fn remove_polling(&self, mut target: Client) {
+        if self.shutting_down.load(Ordering::Relaxed) {
+            // the queue was flushed by the last poll, the peer will come back to another node
+            self.unregister(target.peer_id.as_str(), target.generation).await;
+            return;
+        }
+        let generation = target.generation;
+        let peer_id = target.peer_id.clone();
         target.http = None;
-        self.map.lock().unwrap().insert(target.peer_id.clone(), target);
+        // senders still holding the http sender of this poll must not remove the client
+        target.generation = client::next_generation();
+        if let Some(mut entry) = self.map.get_mut(&peer_id) {
+            if entry.generation == generation {
+                *entry = target;
+                return;
+            }
+        }
+        // a duplicate connection of the peer id ends with its poll, its next poll logs in again
+        self.remove(peer_id.as_str(), generation).await;
+    }
+
+    /// Close websocket clients with "going away" and flush the queues of polling clients.
+    /// Returns the number of messages discarded with polling clients which were between two polls.
+    pub async fn shutdown(&self) -> usize {
+        self.shutting_down.store(true, Ordering::Relaxed);
+        let mut idle_polling = Vec::new();
+        for entry in self.map.iter() {
+            let client = entry.value();
+            if client.is_polling && client.http.is_none() {
+                idle_polling.push((client.peer_id.clone(), client.generation));
+            } else {
+                client.go_away();
+            }
+        }
+        // polling duplicates are removed after each poll, they are never idle
+        for entry in self.duplicates.iter() {
+            entry.value().iter().for_each(Client::go_away);
+        }
+        // polling clients between two polls have nothing to wait for, the listeners
+        // stopped accepting so their next poll cannot come
+        let mut discarded = 0;
+        for (peer_id, generation) in idle_polling {
+            if let Some(client) = self.remove(peer_id.as_str(), generation).await {
+                discarded += client.msg_queue.lock().unwrap().len();
+            }
+        }
+        if discarded > 0 {
+            warn!("shutdown discarded", discarded, "messages queued for polling clients");
+        }
+        discarded
+    }
+
+    /// Wait until every client left or the timeout elapsed, returns whether all clients left.
+    pub async fn wait_drained(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while !self.map.is_empty() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        true
+    }
+}
+
+// Number of signals in a message, the items of a batch are acked one by one.
+fn batch_len(msg: &SignalMsg) -> usize {
+    match (msg.action.as_deref(), msg.data.as_ref()) {
+        (Some("signals"), Some(Value::Array(items))) => items.len(),
+        _ => 1,
     }
 }
 
 fn key_for_filter(from: &str, to: &str) -> String {
     format!("{:}{:}", from, to)
     // from.to_owned() +to
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::time::Instant as StdInstant;
+    use serde_json::json;
+    use crate::client::WsSender;
+    use crate::close_code;
+    use crate::config::{Connection, Offline};
+    use crate::testing::{new_hub, polling_client, ws_client, PEER};
+    use super::*;
+
+    const PEERS: usize = 10_000;
+    const MSGS_PER_WORKER: usize = 100_000;
+
+    #[tokio::test]
+    async fn logging_in_clients_keep_their_app_under_max_clients() {
+        let hub = new_hub();
+        let mut client = ws_client(&hub);
+        client.app = Some(Arc::from("app1"));
+        let slot = hub.reserve("app1", 2).unwrap();
+        assert!(hub.login(client).await);
+        drop(slot);
+        let reserved = hub.reserve("app1", 2).unwrap();
+        // the logged in client and the reserved place fill the app
+        assert!(hub.reserve("app1", 2).is_none());
+        drop(reserved);
+        assert!(hub.reserve("app1", 2).is_some());
+    }
+
+    async fn generation(hub: &mut Hub) -> Option<u64> {
+        hub.get_client(PEER).await.map(|client| client.generation)
+    }
+
+    #[tokio::test]
+    async fn cleanup_of_old_socket_keeps_reconnected_client() {
+        let mut hub = new_hub();
+        let old = ws_client(&hub);
+        let new = ws_client(&hub);
+        assert!(hub.login(old.clone()).await);
+        assert!(hub.login(new.clone()).await);
+        // the old socket closes after the peer reconnected
+        assert!(!hub.unregister(PEER, old.generation).await);
+        assert_eq!(generation(&mut hub).await, Some(new.generation));
+    }
+
+    #[tokio::test]
+    async fn old_long_poll_does_not_replace_reconnected_client() {
+        let mut hub = new_hub();
+        let poll = polling_client(&hub);
+        assert!(hub.login(poll.clone()).await);
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let polling = hub.resume_polling(poll, tx).unwrap();
+        // the peer reconnects by websocket while the long poll is pending
+        let ws = ws_client(&hub);
+        assert!(hub.login(ws.clone()).await);
+        hub.remove_polling(polling).await;
+        let client = hub.get_client(PEER).await.unwrap();
+        assert!(!client.is_polling);
+        assert_eq!(client.generation, ws.generation);
+    }
+
+    #[tokio::test]
+    async fn long_poll_does_not_bring_back_unregistered_client() {
+        let mut hub = new_hub();
+        let poll = polling_client(&hub);
+        assert!(hub.login(poll.clone()).await);
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let polling = hub.resume_polling(poll, tx).unwrap();
+        assert!(hub.unregister(PEER, polling.generation).await);
+        hub.remove_polling(polling).await;
+        assert_eq!(generation(&mut hub).await, None);
+    }
+
+    #[tokio::test]
+    async fn sender_of_finished_poll_does_not_remove_client() {
+        let mut hub = new_hub();
+        let poll = polling_client(&hub);
+        assert!(hub.login(poll.clone()).await);
+        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        let polling = hub.resume_polling(poll, tx).unwrap();
+        hub.remove_polling(polling.clone()).await;
+        drop(rx);
+        // a message routed to a copy taken during the poll fails, the client is still registered
+        assert!(!hub.unregister(PEER, polling.generation).await);
+        assert!(generation(&mut hub).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn poll_is_not_resumed_after_relogin() {
+        let mut hub = new_hub();
+        let poll = polling_client(&hub);
+        assert!(hub.login(poll.clone()).await);
+        let read = hub.get_client(PEER).await.unwrap();
+        let ws = ws_client(&hub);
+        assert!(hub.login(ws.clone()).await);
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        assert!(hub.resume_polling(read, tx).is_none());
+        assert_eq!(generation(&mut hub).await, Some(ws.generation));
+    }
+
+    #[tokio::test]
+    async fn default_peer_with_app_prefix_is_not_reachable_by_the_app() {
+        let mut hub = new_hub();
+        // registered before tenants were enabled by a reload
+        let (tx, mut rx) = WsSender::channel(hub.options());
+        assert!(hub.login(Client::new("app1/peer01", tx, hub.options())).await);
+        let (tx, _) = WsSender::channel(hub.options());
+        let mut sender = Client::new("app1/sender01", tx, hub.options());
+        sender.app = Some(Arc::from("app1"));
+        assert!(hub.login(sender).await);
+        hub.process_message(signal("peer01"), "app1/sender01", Some("app1")).await;
+        assert!(rx.try_recv().is_err());
+        assert_eq!(hub.metrics().peer_not_found.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn polling_login_kicks_websocket_which_did_not_upgrade() {
+        let mut hub = new_hub();
+        let ws = ws_client(&hub);
+        assert!(hub.login(ws.clone()).await);
+        let poll = polling_client(&hub);
+        assert!(hub.login(poll.clone()).await);
+        assert_eq!(ws.ws.as_ref().unwrap().close_code(), Some(close_code::DUPLICATE_LOGIN));
+        assert_eq!(generation(&mut hub).await, Some(poll.generation));
+    }
+
+    #[tokio::test]
+    async fn polling_client_upgrades_to_websocket() {
+        // the socket takes one of the two queued messages
+        let options = Connection { ws_queue_size: Some(1), ..Connection::default() }.options().unwrap();
+        let mut hub = Hub::new(options, SignalFilter::new(None), None, None);
+        let (tx, mut sender_rx) = WsSender::channel(options);
+        assert!(hub.login(Client::new("peer000002", tx, options)).await);
+        let poll = polling_client(&hub);
+        assert!(hub.login(poll.clone()).await);
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let polling = hub.resume_polling(poll, tx).unwrap();
+        for _ in 0..2 {
+            hub.process_message(signal(PEER), "peer000002", None).await;
+        }
+        rx.recv().await.unwrap();
+
+        let (sender, mut queued) = WsSender::channel(hub.options());
+        let ws = Client::new(PEER, sender, hub.options());
+        assert!(hub.upgrade(ws.clone()).await);
+        assert!(queued.recv().await.unwrap().contains("peer000002"));
+        // the sender of the other one is told it was lost
+        let notice: Value = serde_json::from_str(&sender_rx.try_recv().unwrap()).unwrap();
+        assert_eq!(notice["action"], "dropped");
+        assert_eq!(notice["from_peer_id"], PEER);
+        assert_eq!(hub.counters().ws_dropped.load(Ordering::Relaxed), 1);
+        // the pending long poll returns nothing and does not replace the websocket
+        assert!(polling.clone().get_queued_msgs().is_empty());
+        assert!(polling.is_replaced());
+        assert!(!hub.login(polling_client(&hub)).await);
+        // a sender which read the client before the upgrade reaches the websocket
+        hub.send_json_to_client(polling.clone(), signal(PEER)).await;
+        assert!(queued.recv().await.is_some());
+        hub.remove_polling(polling).await;
+        let client = hub.get_client(PEER).await.unwrap();
+        assert!(!client.is_polling);
+        assert_eq!(client.generation, ws.generation);
+        assert_eq!(hub.counters().upgrades.load(Ordering::Relaxed), 1);
+    }
+
+    // A client of PEER and whether a message reached it since the last call.
+    fn connect(hub: &Hub, polling: bool) -> (Client, Box<dyn FnMut() -> bool>) {
+        if polling {
+            let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+            let client = Client::new_poll(PEER, tx, hub.options());
+            let queue = client.msg_queue.clone();
+            (client, Box::new(move || {
+                let _ = rx.try_recv();
+                queue.lock().unwrap().drain(..).count() > 0
+            }))
+        } else {
+            let (tx, mut rx) = WsSender::channel(hub.options());
+            (Client::new(PEER, tx, hub.options()), Box::new(move || rx.try_recv().is_ok()))
+        }
+    }
+
+    fn duplicate_hub(policy: DuplicateLogin) -> Hub {
+        let options = Connection { duplicate_login: Some(policy), ..Connection::default() }.options().unwrap();
+        Hub::new(options, SignalFilter::new(None), None, None)
+    }
+
+    #[tokio::test]
+    async fn duplicate_logins_follow_the_policy_for_both_transports() {
+        for policy in [DuplicateLogin::Reject, DuplicateLogin::Kick, DuplicateLogin::Allow] {
+            // a websocket without the upgrade flag joining a polling client is a duplicate too
+            for (old_polling, new_polling) in [(false, false), (true, true), (true, false), (false, true)] {
+                let case = format!("{:?}, polling {} then {}", policy, old_polling, new_polling);
+                let mut hub = duplicate_hub(policy);
+                let (old, mut old_received) = connect(&hub, old_polling);
+                assert!(hub.login(old.clone()).await);
+                let (new, mut new_received) = connect(&hub, new_polling);
+                assert_eq!(hub.rejects_login(PEER, false), policy == DuplicateLogin::Reject, "{}", case);
+                assert_eq!(hub.login(new.clone()).await, policy != DuplicateLogin::Reject, "{}", case);
+                hub.process_message(signal(PEER), "peer000002", None).await;
+                let (old_gets, new_gets) = match policy {
+                    DuplicateLogin::Reject => (true, false),
+                    DuplicateLogin::Kick => (false, true),
+                    DuplicateLogin::Allow => (true, true),
+                };
+                assert_eq!(old_received(), old_gets, "{}", case);
+                assert_eq!(new_received(), new_gets, "{}", case);
+                assert_eq!(old.is_replaced(), policy == DuplicateLogin::Kick, "{}", case);
+                if let Some(ws) = old.ws.as_ref() {
+                    assert_eq!(ws.close_code() == Some(close_code::DUPLICATE_LOGIN), policy == DuplicateLogin::Kick, "{}", case);
+                }
+                let primary = if policy == DuplicateLogin::Reject { &old } else { &new };
+                assert_eq!(generation(&mut hub).await, Some(primary.generation), "{}", case);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn upgrades_are_not_duplicate_logins() {
+        let hub = duplicate_hub(DuplicateLogin::Reject);
+        assert!(hub.login(polling_client(&hub)).await);
+        assert!(hub.rejects_login(PEER, false));
+        assert!(!hub.rejects_login(PEER, true));
+        assert!(hub.upgrade(ws_client(&hub)).await);
+        // the websocket is no polling client anymore
+        assert!(hub.rejects_login(PEER, true));
+        assert!(!hub.upgrade(ws_client(&hub)).await);
+    }
+
+    #[tokio::test]
+    async fn allowed_duplicates_stay_until_their_connection_leaves() {
+        let mut hub = duplicate_hub(DuplicateLogin::Allow);
+        let (first, mut first_received) = connect(&hub, false);
+        let (second, _) = connect(&hub, false);
+        let (third, mut third_received) = connect(&hub, true);
+        for client in [&first, &second, &third] {
+            assert!(hub.login(client.clone()).await);
+        }
+        assert_eq!(hub.num_client().await, 1);
+        // a duplicate leaves, the primary connection stays
+        assert!(hub.unregister(PEER, second.generation).await);
+        assert_eq!(generation(&mut hub).await, Some(third.generation));
+        // the poll of the primary connection ends, the latest duplicate takes its place
+        assert!(hub.unregister(PEER, third.generation).await);
+        assert_eq!(generation(&mut hub).await, Some(first.generation));
+        hub.process_message(signal(PEER), "peer000002", None).await;
+        assert!(first_received());
+        assert!(!third_received());
+        assert!(!hub.unregister(PEER, third.generation).await);
+        assert!(hub.unregister(PEER, first.generation).await);
+        assert!(hub.get_client(PEER).await.is_none());
+        assert!(hub.duplicates.is_empty());
+    }
+
+    #[tokio::test]
+    async fn polling_duplicates_leave_with_their_poll() {
+        let mut hub = duplicate_hub(DuplicateLogin::Allow);
+        let (first, _) = connect(&hub, true);
+        let (second, _) = connect(&hub, true);
+        assert!(hub.login(first.clone()).await);
+        assert!(hub.login(second.clone()).await);
+        hub.remove_polling(first).await;
+        assert!(hub.duplicates.is_empty());
+        // the primary polling client stays registered between two polls
+        hub.remove_polling(second).await;
+        assert!(hub.get_client(PEER).await.is_some_and(|client| client.is_polling && !client.is_waiting()));
+    }
+
+    fn signal(to: &str) -> Arc<SignalMsg> {
+        Arc::new(SignalMsg {
+            action: Some("signal".to_string()),
+            to_peer_id: Some(to.to_string()),
+            data: Some(json!({"sdp": "offer"})),
+            ..SignalMsg::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn full_polling_queues_apply_the_overflow_policy() {
+        for (policy, kept, notice) in [
+            (OverflowPolicy::DropOldest, [2, 3], "dropped"),
+            (OverflowPolicy::DropNewest, [1, 2], "dropped"),
+            (OverflowPolicy::Reject, [1, 2], "reject"),
+        ] {
+            let options = Connection { polling_queue_size: Some(2), polling_overflow: Some(policy), ..Connection::default() }.options().unwrap();
+            let mut hub = Hub::new(options, SignalFilter::new(None), None, None);
+            let (tx, mut sender_rx) = WsSender::channel(options);
+            assert!(hub.login(Client::new("sender01", tx, options)).await);
+            let (tx, _rx) = tokio::sync::mpsc::channel(1);
+            let mut poll = Client::new_poll(PEER, tx, options);
+            assert!(hub.login(poll.clone()).await);
+            for n in 1..=3 {
+                let mut msg = signal(PEER).as_ref().clone();
+                msg.data = Some(json!({"n": n}));
+                hub.process_message(Arc::new(msg), "sender01", None).await;
+            }
+            let queued: Vec<Value> = poll.get_queued_msgs().iter().map(|msg| msg.data.clone().unwrap()["n"].clone()).collect();
+            assert_eq!(queued, kept.map(|n| json!(n)), "{:?}", policy);
+            // the sender learns its signal was lost
+            let sent: Value = serde_json::from_str(&sender_rx.try_recv().unwrap()).unwrap();
+            assert_eq!(sent, json!({"action": notice, "from_peer_id": PEER, "reason": "message queue of peer is full"}));
+            assert!(sender_rx.try_recv().is_err());
+            assert_eq!(hub.counters().polling_dropped.load(Ordering::Relaxed), 1);
+            assert_eq!(hub.dropped_by_peer(10), vec![(PEER.to_string(), 1)]);
+        }
+    }
+
+    fn offline_hub(ttl: u64) -> Hub {
+        let offline = OfflineBuffer::from_config(Some(&Offline { enable: true, ttl: Some(ttl), size: Some(2), capacity: None }));
+        Hub::new(Connection::default().options().unwrap(), SignalFilter::new(None), offline, None)
+    }
+
+    #[tokio::test]
+    async fn signals_are_held_until_peer_reconnects() {
+        let mut hub = offline_hub(10);
+        let (tx, mut sender_rx) = WsSender::channel(hub.options());
+        assert!(hub.login(Client::new("sender01", tx, hub.options())).await);
+        let old = ws_client(&hub);
+        assert!(hub.login(old.clone()).await);
+        assert!(hub.unregister(PEER, old.generation).await);
+
+        for _ in 0..3 {
+            hub.process_message(signal(PEER), "sender01", None).await;
+        }
+        // the third signal does not fit into the buffer of the peer
+        assert!(sender_rx.recv().await.unwrap().contains("\"dropped\""));
+
+        let (tx, mut rx) = WsSender::channel(hub.options());
+        assert!(hub.login(Client::new(PEER, tx, hub.options())).await);
+        for _ in 0..2 {
+            assert!(rx.recv().await.unwrap().contains("offer"));
+        }
+        assert_eq!(hub.counters().offline_delivered.load(Ordering::Relaxed), 2);
+        assert!(sender_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn held_signals_without_room_are_reported_to_their_sender() {
+        let offline = OfflineBuffer::from_config(Some(&Offline { enable: true, ttl: Some(10), size: Some(2), capacity: None }));
+        let options = Connection { ws_queue_size: Some(1), ..Connection::default() }.options().unwrap();
+        let mut hub = Hub::new(options, SignalFilter::new(None), offline, None);
+        let (tx, mut sender_rx) = WsSender::channel(options);
+        assert!(hub.login(Client::new("sender01", tx, options)).await);
+        let old = ws_client(&hub);
+        assert!(hub.login(old.clone()).await);
+        assert!(hub.unregister(PEER, old.generation).await);
+        for _ in 0..2 {
+            hub.process_message(signal(PEER), "sender01", None).await;
+        }
+
+        let (tx, mut rx) = WsSender::channel(options);
+        assert!(hub.login(Client::new(PEER, tx, options)).await);
+        assert!(rx.try_recv().unwrap().contains("offer"));
+        assert!(rx.try_recv().is_err());
+        assert!(sender_rx.try_recv().unwrap().contains("\"dropped\""));
+        assert_eq!(hub.counters().offline_delivered.load(Ordering::Relaxed), 1);
+        assert_eq!(hub.counters().ws_dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn held_signals_are_reported_not_found_after_ttl() {
+        let mut hub = offline_hub(1);
+        let (tx, mut sender_rx) = WsSender::channel(hub.options());
+        assert!(hub.login(Client::new("sender01", tx, hub.options())).await);
+        let old = ws_client(&hub);
+        assert!(hub.login(old.clone()).await);
+        assert!(hub.unregister(PEER, old.generation).await);
+
+        let mut msg = signal(PEER).as_ref().clone();
+        msg.id = Some(json!(7));
+        msg.ack = Some(true);
+        hub.process_message(Arc::new(msg), "sender01", None).await;
+        let queued: Value = serde_json::from_str(&sender_rx.try_recv().unwrap()).unwrap();
+        assert_eq!(queued["status"], "queued");
+        assert!(sender_rx.try_recv().is_err());
+        let notice = tokio::time::timeout(Duration::from_secs(5), sender_rx.recv()).await.unwrap().unwrap();
+        assert!(notice.contains(PEER) && !notice.contains("offer"));
+        assert_eq!(hub.metrics().peer_not_found.load(Ordering::Relaxed), 1);
+        let expired: Value = serde_json::from_str(&sender_rx.recv().await.unwrap()).unwrap();
+        assert_eq!(expired, json!({"action": "ack", "from_peer_id": PEER, "id": 7, "status": "peer_not_found"}));
+    }
+
+    #[tokio::test]
+    async fn acks_report_delivery_of_every_signal() {
+        let mut hub = new_hub();
+        let (tx, mut sender_rx) = WsSender::channel(hub.options());
+        assert!(hub.login(Client::new("sender01", tx, hub.options())).await);
+        let (tx, mut rx) = WsSender::channel(hub.options());
+        assert!(hub.login(Client::new(PEER, tx, hub.options())).await);
+        let mut next_ack = || serde_json::from_str::<Value>(&sender_rx.try_recv().unwrap()).unwrap();
+
+        let mut msg = signal(PEER).as_ref().clone();
+        msg.id = Some(json!(1));
+        msg.ack = Some(true);
+        hub.process_message(Arc::new(msg.clone()), "sender01", None).await;
+        assert_eq!(next_ack(), json!({"action": "ack", "from_peer_id": PEER, "id": 1, "status": "delivered"}));
+        // the receiving peer does not see the ack request
+        let received: Value = serde_json::from_str(&rx.recv().await.unwrap()).unwrap();
+        assert!(received.get("id").is_none() && received.get("ack").is_none());
+
+        let batch = SignalMsg {
+            action: Some("signals".to_string()),
+            data: Some(json!([{"sdp": 1}, {"sdp": 2}])),
+            id: Some(json!("b")),
+            ..msg.clone()
+        };
+        hub.process_message(Arc::new(batch.clone()), "sender01", None).await;
+        assert_eq!(next_ack()["results"], json!(["delivered", "delivered"]));
+
+        let mut missing = batch.clone();
+        missing.to_peer_id = Some("peer000009".to_string());
+        hub.process_message(Arc::new(missing.clone()), "sender01", None).await;
+        // the empty signal of peer not found comes first
+        assert!(next_ack().get("data").is_none());
+        assert_eq!(next_ack()["results"], json!(["peer_not_found", "peer_not_found"]));
+        hub.process_message(Arc::new(missing), "sender01", None).await;
+        assert_eq!(next_ack()["results"], json!(["filtered", "filtered"]));
+
+        hub.rate_limited(&msg, "sender01", None).await;
+        assert_eq!(next_ack()["status"], json!("rate_limited"));
+        msg.ack = None;
+        hub.process_message(Arc::new(msg), "sender01", None).await;
+        assert!(sender_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn notices_for_a_full_sender_queue_are_not_reported_to_the_target() {
+        for policy in [OverflowPolicy::DropOldest, OverflowPolicy::DropNewest, OverflowPolicy::Reject] {
+            let options = Connection { polling_queue_size: Some(1), polling_overflow: Some(policy), ..Connection::default() }.options().unwrap();
+            let mut hub = Hub::new(options, SignalFilter::new(None), None, None);
+            let (tx, _poll) = tokio::sync::mpsc::channel(1);
+            let mut sender = Client::new_poll("sender01", tx, options);
+            assert!(hub.login(sender.clone()).await);
+            let (tx, mut rx) = WsSender::channel(options);
+            assert!(hub.login(Client::new(PEER, tx, options)).await);
+            // the queue of the sender is full of a notice, its ack has no room
+            hub.process_message(signal("peer000009"), "sender01", None).await;
+            let mut msg = signal(PEER).as_ref().clone();
+            msg.ack = Some(true);
+            hub.process_message(Arc::new(msg.clone()), "sender01", None).await;
+            // the empty signal of peer not found, then the ack
+            msg.to_peer_id = Some("peer000008".to_string());
+            hub.process_message(Arc::new(msg), "sender01", None).await;
+            let received: Value = serde_json::from_str(&rx.try_recv().unwrap()).unwrap();
+            assert_eq!(received["action"], "signal", "{:?}", policy);
+            assert!(rx.try_recv().is_err(), "{:?}", policy);
+            assert_eq!(sender.get_queued_msgs().len(), 1, "{:?}", policy);
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_websockets_and_ends_polls() {
+        let mut hub = new_hub();
+        let ws = ws_client(&hub);
+        assert!(hub.login(ws.clone()).await);
+        let (tx, mut poll_rx) = tokio::sync::mpsc::channel(1);
+        let poll = Client::new_poll("peer000002", tx, hub.options());
+        assert!(hub.login(poll.clone()).await);
+        let (tx, _) = tokio::sync::mpsc::channel(1);
+        let idle = Client::new_poll("peer000003", tx, hub.options());
+        assert!(hub.login(idle.clone()).await);
+        hub.remove_polling(idle).await;
+        for to in ["peer000002", "peer000003", "peer000003"] {
+            hub.process_message(signal(to), PEER, None).await;
+        }
+        poll_rx.recv().await.unwrap();
+
+        // the messages of the idle client are lost, the pending poll takes its own
+        assert_eq!(hub.shutdown().await, 2);
+        assert_eq!(ws.ws.as_ref().unwrap().close_code(), Some(close_code::GOING_AWAY));
+        assert!(hub.get_client("peer000003").await.is_none());
+        assert!(!hub.wait_drained(Duration::from_millis(200)).await);
+        let mut poll = hub.get_client("peer000002").await.unwrap();
+        hub.remove_polling(poll.clone()).await;
+        assert_eq!(poll.get_queued_msgs().len(), 1);
+        assert!(hub.unregister(PEER, ws.generation).await);
+        assert!(hub.wait_drained(Duration::from_millis(200)).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn reconnect_racing_with_cleanup_keeps_newest_connection() {
+        let mut hub = new_hub();
+        for _ in 0..1000 {
+            let old = ws_client(&hub);
+            assert!(hub.login(old.clone()).await);
+            let new = ws_client(&hub);
+            let cleanup = {
+                let hub = hub.clone();
+                tokio::spawn(async move { hub.unregister(PEER, old.generation).await })
+            };
+            let reconnect = {
+                let hub = hub.clone();
+                let new = new.clone();
+                tokio::spawn(async move { hub.login(new).await })
+            };
+            cleanup.await.unwrap();
+            assert!(reconnect.await.unwrap());
+            assert_eq!(generation(&mut hub).await, Some(new.generation));
+            assert!(hub.unregister(PEER, new.generation).await);
+        }
+    }
+
+    // cargo test --release -- --ignored --nocapture bench_routing
+    #[test]
+    #[ignore]
+    fn bench_routing_scales_with_cores() {
+        let cores = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let peer_ids: Arc<Vec<String>> = Arc::new((0..PEERS).map(|i| format!("peer{:06}", i)).collect());
+        let mut workers = 1;
+        while workers <= cores {
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(workers)
+                .enable_all()
+                .build()
+                .unwrap();
+            let peer_ids = peer_ids.clone();
+            let rate = runtime.block_on(async move {
+                let options = Connection::default().options().unwrap();
+                let hub = Hub::new(options, SignalFilter::new(None), None, None);
+                for peer_id in peer_ids.iter() {
+                    let (tx, mut rx) = WsSender::channel(options);
+                    hub.login(Client::new(peer_id, tx.clone(), options)).await;
+                    // read like the websocket writer, so the bounded queues never fill up
+                    tokio::spawn(async move {
+                        while let Some(msg) = rx.recv().await {
+                            tx.consumed(msg.len());
+                        }
+                    });
+                }
+                let start = StdInstant::now();
+                let tasks = (0..workers).map(|w| {
+                    let mut hub = hub.clone();
+                    let peer_ids = peer_ids.clone();
+                    tokio::spawn(async move {
+                        for n in 0..MSGS_PER_WORKER {
+                            let from = &peer_ids[(w * 7919 + n) % PEERS];
+                            let msg = SignalMsg {
+                                action: Some("signal".to_string()),
+                                to_peer_id: Some(peer_ids[(n * 31 + w + 1) % PEERS].clone()),
+                                data: Some(json!({"type": "offer"})),
+                                ..SignalMsg::default()
+                            };
+                            hub.process_message(Arc::new(msg), from, None).await;
+                        }
+                    })
+                }).collect::<Vec<_>>();
+                for task in tasks {
+                    task.await.unwrap();
+                }
+                let rate = (workers * MSGS_PER_WORKER) as f64 / start.elapsed().as_secs_f64();
+                // otherwise the drop and evict path was measured instead of routing
+                assert_eq!(hub.counters().ws_dropped.load(Ordering::Relaxed), 0);
+                assert_eq!(hub.counters().slow_consumer_evictions.load(Ordering::Relaxed), 0);
+                rate
+            });
+            println!("workers {:>3}: {:>12.0} msgs/s", workers, rate);
+            workers *= 2;
+        }
+    }
+}
@@ -0,0 +1,265 @@
+#![deny(unused_imports)]
+use std::sync::Arc;
+use anyhow::{anyhow, Result};
+use jsonwebtoken::jwk::{AlgorithmParameters, JwkSet};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde_json::{Map, Value};
+use crate::config::{Jwt, Security, SecurityMode};
+
+const DEFAULT_ID_CLAIM: &str = "sub";
+
+/// Claims of a verified token, kept with the client for policy checks.
+pub type Claims = Map<String, Value>;
+
+struct Key {
+    kid: Option<String>,
+    alg: Algorithm,
+    key: DecodingKey,
+}
+
+/// Verifies HS256, RS256 and ES256 tokens against local keys and a JWKS file.
+pub struct JwtVerifier {
+    keys: Vec<Key>,
+    audience: Option<Vec<String>>,
+    issuer: Option<Vec<String>>,
+    id_claim: String,
+    leeway: u64,
+}
+
+impl JwtVerifier {
+
+    /// Loads the keys when the jwt mode is enabled.
+    pub fn from_security(security: Option<&Security>) -> Result<Option<Arc<Self>>> {
+        match security {
+            Some(s) if s.enable && s.mode == SecurityMode::Jwt => {
+                let jwt = s.jwt.as_ref().ok_or_else(|| anyhow!("security.jwt is required in jwt mode"))?;
+                Ok(Some(Arc::new(Self::new(jwt)?)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn new(config: &Jwt) -> Result<Self> {
+        let mut keys = Vec::new();
+        if let Some(secret) = config.secret.as_ref() {
+            keys.push(Key { kid: None, alg: Algorithm::HS256, key: DecodingKey::from_secret(secret.as_bytes()) });
+        }
+        for item in config.public_keys.iter().flatten() {
+            let pem = std::fs::read(&item.path)?;
+            let (alg, key) = match DecodingKey::from_rsa_pem(&pem) {
+                Ok(key) => (Algorithm::RS256, key),
+                Err(_) => (Algorithm::ES256, DecodingKey::from_ec_pem(&pem)
+                    .map_err(|e| anyhow!("{} is neither a rsa nor an ec public key: {}", item.path, e))?),
+            };
+            keys.push(Key { kid: item.kid.clone(), alg, key });
+        }
+        if let Some(path) = config.jwks.as_ref() {
+            let set: JwkSet = serde_json::from_slice(&std::fs::read(path)?)?;
+            for jwk in set.keys.iter() {
+                let alg = match &jwk.algorithm {
+                    AlgorithmParameters::RSA(_) => Algorithm::RS256,
+                    AlgorithmParameters::EllipticCurve(_) => Algorithm::ES256,
+                    AlgorithmParameters::OctetKey(_) => Algorithm::HS256,
+                    // not one of the supported algorithms
+                    AlgorithmParameters::OctetKeyPair(_) => continue,
+                };
+                keys.push(Key { kid: jwk.common.key_id.clone(), alg, key: DecodingKey::from_jwk(jwk)? });
+            }
+        }
+        if keys.is_empty() {
+            return Err(anyhow!("security.jwt has no keys"))
+        }
+        Ok(Self {
+            keys,
+            audience: config.audience.clone(),
+            issuer: config.issuer.clone().map(|issuer| vec![issuer]),
+            id_claim: config.id_claim.clone().unwrap_or(DEFAULT_ID_CLAIM.to_string()),
+            leeway: config.leeway.unwrap_or(0),
+        })
+    }
+
+    /// Verify the signature, exp, nbf, aud and iss of a token issued to the peer id.
+    pub fn verify(&self, id: &str, token: &str) -> Result<Claims> {
+        let header = decode_header(token)?;
+        if !matches!(header.alg, Algorithm::HS256 | Algorithm::RS256 | Algorithm::ES256) {
+            return Err(anyhow!("algorithm {:?} is not supported", header.alg))
+        }
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = self.leeway;
+        validation.validate_nbf = true;
+        match self.audience.as_ref() {
+            Some(audience) => validation.set_audience(audience),
+            None => validation.validate_aud = false,
+        }
+        if let Some(issuer) = self.issuer.as_ref() {
+            validation.set_issuer(issuer);
+        }
+        let mut last_err = anyhow!("no key for algorithm {:?} and key id {:?}", header.alg, header.kid);
+        // the kid of the token narrows down the keys, keys without a kid match any token
+        for key in self.keys.iter().filter(|key| key.alg == header.alg
+            && (key.kid.is_none() || header.kid.is_none() || key.kid == header.kid)) {
+            match decode::<Claims>(token, &key.key, &validation) {
+                Ok(data) => {
+                    return match data.claims.get(&self.id_claim).and_then(|v| v.as_str()) {
+                        Some(claimed) if claimed == id => Ok(data.claims),
+                        _ => Err(anyhow!("claim {} does not match the peer id", self.id_claim)),
+                    }
+                }
+                Err(e) => last_err = e.into(),
+            }
+        }
+        Err(last_err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+    use crate::config::JwtKey;
+    use crate::testing::{now, PEER};
+    use super::*;
+
+    fn testdata(name: &str) -> String {
+        format!("{}/testdata/jwt/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    // RS256 and ES256 keys of PEM files and of a JWKS file, each with a kid.
+    fn key_files_verifier() -> JwtVerifier {
+        let key = |kid: &str, name: &str| JwtKey { kid: Some(kid.to_string()), path: testdata(name) };
+        JwtVerifier::new(&Jwt {
+            secret: None,
+            public_keys: Some(vec![key("pem-rsa", "rsa.pem"), key("pem-ec", "ec.pem")]),
+            jwks: Some(testdata("jwks.json")),
+            audience: None,
+            issuer: None,
+            id_claim: None,
+            leeway: None,
+        }).unwrap()
+    }
+
+    // A token of PEER signed with the private key in the file.
+    fn signed(alg: Algorithm, kid: &str, name: &str) -> String {
+        let pem = std::fs::read(testdata(name)).unwrap();
+        let key = match alg {
+            Algorithm::ES256 => EncodingKey::from_ec_pem(&pem).unwrap(),
+            _ => EncodingKey::from_rsa_pem(&pem).unwrap(),
+        };
+        let header = Header { kid: Some(kid.to_string()), ..Header::new(alg) };
+        encode(&header, &json!({"sub": PEER, "exp": now() + 60}), &key).unwrap()
+    }
+
+    fn verifier() -> JwtVerifier {
+        let config = Jwt {
+            secret: Some("default-secret".to_string()),
+            public_keys: None,
+            jwks: None,
+            audience: Some(vec!["signal".to_string()]),
+            issuer: Some("issuer".to_string()),
+            id_claim: None,
+            leeway: None,
+        };
+        let mut verifier = JwtVerifier::new(&config).unwrap();
+        for (kid, secret) in [("a", "secret-a"), ("b", "secret-b")] {
+            verifier.keys.push(Key { kid: Some(kid.to_string()), alg: Algorithm::HS256, key: DecodingKey::from_secret(secret.as_bytes()) });
+        }
+        verifier
+    }
+
+    fn token(kid: Option<&str>, secret: &str, claims: Value) -> String {
+        let header = Header { kid: kid.map(str::to_string), ..Header::new(Algorithm::HS256) };
+        encode(&header, &claims, &EncodingKey::from_secret(secret.as_bytes())).unwrap()
+    }
+
+    fn claims() -> Value {
+        json!({"sub": PEER, "aud": "signal", "iss": "issuer", "exp": now() + 60})
+    }
+
+    #[test]
+    fn valid_tokens_are_accepted() {
+        let verifier = verifier();
+        let verified = verifier.verify(PEER, &token(None, "default-secret", claims())).unwrap();
+        assert_eq!(verified["sub"], PEER);
+        // the kid picks the key, the key without kid still matches any token
+        assert!(verifier.verify(PEER, &token(Some("b"), "secret-b", claims())).is_ok());
+        assert!(verifier.verify(PEER, &token(Some("b"), "default-secret", claims())).is_ok());
+        assert!(verifier.verify(PEER, &token(Some("a"), "secret-b", claims())).is_err());
+        assert!(verifier.verify(PEER, &token(Some("c"), "secret-c", claims())).is_err());
+    }
+
+    #[test]
+    fn invalid_claims_are_rejected() {
+        let verifier = verifier();
+        let with = |key: &str, value: Value| {
+            let mut claims = claims();
+            claims[key] = value;
+            token(None, "default-secret", claims)
+        };
+        assert!(verifier.verify(PEER, &with("exp", json!(now() - 60))).is_err());
+        assert!(verifier.verify(PEER, &with("aud", json!("other"))).is_err());
+        assert!(verifier.verify(PEER, &with("iss", json!("other"))).is_err());
+        assert!(verifier.verify(PEER, &with("sub", json!("peer000002"))).is_err());
+        assert!(verifier.verify("peer000002", &token(None, "default-secret", claims())).is_err());
+        assert!(verifier.verify(PEER, "not a token").is_err());
+    }
+
+    #[test]
+    fn id_claim_is_configurable() {
+        let mut verifier = verifier();
+        verifier.id_claim = "peer".to_string();
+        let mut claims = claims();
+        claims["peer"] = json!(PEER);
+        claims["sub"] = json!("user-1");
+        assert!(verifier.verify(PEER, &token(None, "default-secret", claims.clone())).is_ok());
+        claims["peer"] = json!("peer000002");
+        assert!(verifier.verify(PEER, &token(None, "default-secret", claims)).is_err());
+    }
+
+    #[test]
+    fn pem_and_jwks_keys_verify_rs256_and_es256() {
+        let verifier = key_files_verifier();
+        let algs: Vec<(Option<&str>, Algorithm)> = verifier.keys.iter().map(|key| (key.kid.as_deref(), key.alg)).collect();
+        assert_eq!(algs, [
+            (Some("pem-rsa"), Algorithm::RS256),
+            (Some("pem-ec"), Algorithm::ES256),
+            (Some("jwks-rsa"), Algorithm::RS256),
+            (Some("jwks-ec"), Algorithm::ES256),
+        ]);
+        for (alg, kid, name) in [
+            (Algorithm::RS256, "pem-rsa", "rsa.key"),
+            (Algorithm::ES256, "pem-ec", "ec.key"),
+            (Algorithm::RS256, "jwks-rsa", "rsa2.key"),
+            (Algorithm::ES256, "jwks-ec", "ec2.key"),
+        ] {
+            assert!(verifier.verify(PEER, &signed(alg, kid, name)).is_ok(), "{}", kid);
+        }
+    }
+
+    #[test]
+    fn tokens_with_another_kid_or_algorithm_are_rejected() {
+        let verifier = key_files_verifier();
+        // the kid of another key of the same algorithm
+        assert!(verifier.verify(PEER, &signed(Algorithm::RS256, "jwks-rsa", "rsa.key")).is_err());
+        assert!(verifier.verify(PEER, &signed(Algorithm::ES256, "pem-ec", "ec2.key")).is_err());
+        // the kid of a key of another algorithm
+        assert!(verifier.verify(PEER, &signed(Algorithm::ES256, "pem-rsa", "ec.key")).is_err());
+        // an algorithm which is not supported, with the right key
+        assert!(verifier.verify(PEER, &signed(Algorithm::RS384, "pem-rsa", "rsa.key")).is_err());
+        assert!(verifier.verify(PEER, &signed(Algorithm::RS256, "unknown", "rsa.key")).is_err());
+    }
+
+    #[test]
+    fn files_without_a_public_key_are_errors() {
+        let config = |path: String| Jwt {
+            secret: None,
+            public_keys: Some(vec![JwtKey { kid: None, path }]),
+            jwks: None,
+            audience: None,
+            issuer: None,
+            id_claim: None,
+            leeway: None,
+        };
+        assert!(JwtVerifier::new(&config(testdata("jwks.json"))).is_err());
+        assert!(JwtVerifier::new(&config(testdata("missing.pem"))).is_err());
+    }
+}
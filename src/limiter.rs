@@ -0,0 +1,434 @@
+#![deny(unused_imports)]
+use std::net::IpAddr;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use anyhow::anyhow;
+use dashmap::DashMap;
+use ratelimit::Ratelimiter;
+use tokio::time::Instant;
+use crate::config::{KeyedRatelimit, Rate, Ratelimit};
+
+// how often buckets which refilled completely are dropped
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// An ip address or a cidr range.
+#[derive(Debug, Clone, Copy)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            None => (s, None),
+            Some((addr, prefix)) => (addr, Some(prefix)),
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| anyhow!("invalid ip {}", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            None => max,
+            Some(prefix) => prefix.trim().parse::<u8>().ok().filter(|p| *p <= max)
+                .ok_or_else(|| anyhow!("invalid prefix {}", s))?,
+        };
+        Ok(Self { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical_ip(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Ipv4 clients of a dual stack listener show up as mapped ipv6 addresses.
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets keyed by ip or peer id.
+struct KeyedLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: DashMap<String, Bucket>,
+}
+
+impl KeyedLimiter {
+
+    fn new(rate: &Rate) -> Self {
+        Self {
+            rate: rate.rate,
+            burst: rate.burst.map(|b| b as f64).unwrap_or(rate.rate.max(1.0)),
+            buckets: DashMap::new(),
+        }
+    }
+
+    fn try_acquire(&self, key: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut bucket = self.buckets.entry(key.to_string())
+            .or_insert(Bucket { tokens: self.burst, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(())
+        }
+        Err(self.wait(bucket.tokens))
+    }
+
+    // Whether a token is available, without taking it.
+    fn check(&self, key: &str) -> Result<(), Duration> {
+        let tokens = match self.buckets.get(key) {
+            None => return Ok(()),
+            Some(bucket) => (bucket.tokens + bucket.updated.elapsed().as_secs_f64() * self.rate).min(self.burst),
+        };
+        if tokens >= 1.0 {
+            return Ok(())
+        }
+        Err(self.wait(tokens))
+    }
+
+    // Give back a token taken for a request which another limit rejected.
+    fn refund(&self, key: &str) {
+        if let Some(mut bucket) = self.buckets.get_mut(key) {
+            bucket.tokens = (bucket.tokens + 1.0).min(self.burst);
+        }
+    }
+
+    fn wait(&self, tokens: f64) -> Duration {
+        Duration::from_secs_f64((1.0 - tokens) / self.rate)
+    }
+
+    // a full bucket is the same as no bucket
+    fn purge(&self) {
+        let now = Instant::now();
+        self.buckets.retain(|_, bucket| {
+            bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * self.rate < self.burst
+        });
+    }
+}
+
+/// Connect and message limits sharing one kind of key.
+#[derive(Default)]
+pub struct KeyedLimiters {
+    connect: Option<KeyedLimiter>,
+    message: Option<KeyedLimiter>,
+}
+
+impl KeyedLimiters {
+
+    pub fn new(config: Option<&KeyedRatelimit>) -> Self {
+        match config {
+            None => Self::default(),
+            Some(c) => Self {
+                connect: c.connect.as_ref().map(KeyedLimiter::new),
+                message: c.message.as_ref().map(KeyedLimiter::new),
+            },
+        }
+    }
+
+    pub fn check_connect(&self, key: &str) -> Result<(), Duration> {
+        match self.connect.as_ref() {
+            Some(limiter) => limiter.try_acquire(key),
+            None => Ok(()),
+        }
+    }
+
+    pub fn check_message(&self, key: &str) -> Result<(), Duration> {
+        match self.message.as_ref() {
+            Some(limiter) => limiter.try_acquire(key),
+            None => Ok(()),
+        }
+    }
+
+    fn purge(&self) {
+        for limiter in [self.connect.as_ref(), self.message.as_ref()].into_iter().flatten() {
+            limiter.purge();
+        }
+    }
+}
+
+/// Rate limits of the whole server, of every client ip and of every peer id.
+pub struct RateLimits {
+    global: Option<Ratelimiter>,
+    per_ip: KeyedLimiters,
+    per_peer: KeyedLimiters,
+    allowlist: Vec<IpNet>,
+    real_ip_header: Option<String>,
+    // proxies whose real_ip_header is trusted
+    trusted_proxies: Vec<IpNet>,
+}
+
+impl RateLimits {
+
+    /// None when rate limiting is disabled.
+    pub fn from_config(config: Option<&Ratelimit>) -> Option<Arc<Self>> {
+        let config = match config {
+            Some(r) if r.enable => r,
+            _ => return None,
+        };
+        let limits = Arc::new(Self {
+            global: config.max_rate.map(|max_rate| Ratelimiter::builder(max_rate, Duration::from_secs(1))
+                .max_tokens(max_rate)
+                .initial_available(max_rate)
+                .build()
+                .unwrap()),
+            per_ip: KeyedLimiters::new(config.per_ip.as_ref()),
+            per_peer: KeyedLimiters::new(config.per_peer.as_ref()),
+            // validated when the config was parsed
+            allowlist: config.allowlist.iter().flatten().filter_map(|net| IpNet::parse(net).ok()).collect(),
+            real_ip_header: config.real_ip_header.clone(),
+            trusted_proxies: config.trusted_proxies.iter().flatten().filter_map(|net| IpNet::parse(net).ok()).collect(),
+        });
+        // stops once a reload replaced these limits
+        let weak: Weak<Self> = Arc::downgrade(&limits);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(PURGE_INTERVAL).await;
+                match weak.upgrade() {
+                    None => break,
+                    Some(limits) => {
+                        limits.per_ip.purge();
+                        limits.per_peer.purge();
+                    }
+                }
+            }
+        });
+        Some(limits)
+    }
+
+    /// Check a new connection, returns how long to wait when it is over a limit.
+    pub fn check_connect(&self, ip: IpAddr, peer_id: &str) -> Result<(), Duration> {
+        if self.is_allowed(ip) {
+            return Ok(())
+        }
+        Self::check(self.per_ip.connect.as_ref(), self.per_peer.connect.as_ref(), ip, peer_id, self.global.as_ref())
+    }
+
+    /// Check a request against the limit of the whole server only.
+    pub fn check_global(&self, ip: IpAddr) -> Result<(), Duration> {
+        match self.global.as_ref() {
+            Some(global) if !self.is_allowed(ip) => global.try_wait(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check a message of a connected client, returns how long to wait when it is over a limit.
+    pub fn check_message(&self, ip: IpAddr, peer_id: &str) -> Result<(), Duration> {
+        if self.is_allowed(ip) {
+            return Ok(())
+        }
+        Self::check(self.per_ip.message.as_ref(), self.per_peer.message.as_ref(), ip, peer_id, None)
+    }
+
+    // A token is taken from every limit or from none, a request rejected by the peer
+    // limit or by the whole server does not count against its ip or its peer id.
+    fn check(per_ip: Option<&KeyedLimiter>, per_peer: Option<&KeyedLimiter>, ip: IpAddr, peer_id: &str, global: Option<&Ratelimiter>) -> Result<(), Duration> {
+        let ip = ip.to_string();
+        if let Some(limiter) = per_ip {
+            limiter.check(&ip)?;
+        }
+        if let Some(limiter) = per_peer {
+            limiter.check(peer_id)?;
+        }
+        if let Some(limiter) = per_ip {
+            limiter.try_acquire(&ip)?;
+        }
+        if let Some(limiter) = per_peer {
+            // another request may have taken the last token meanwhile
+            if let Err(wait) = limiter.try_acquire(peer_id) {
+                if let Some(limiter) = per_ip {
+                    limiter.refund(&ip);
+                }
+                return Err(wait)
+            }
+        }
+        // taken last, a token of the whole server cannot be given back
+        if let Some(global) = global {
+            if let Err(wait) = global.try_wait() {
+                if let Some(limiter) = per_ip {
+                    limiter.refund(&ip);
+                }
+                if let Some(limiter) = per_peer {
+                    limiter.refund(peer_id);
+                }
+                return Err(wait)
+            }
+        }
+        Ok(())
+    }
+
+    fn is_allowed(&self, ip: IpAddr) -> bool {
+        self.allowlist.iter().any(|net| net.contains(ip))
+    }
+
+    /// Header which carries the client ip when running behind a proxy.
+    pub fn real_ip_header(&self) -> Option<&str> {
+        self.real_ip_header.as_deref()
+    }
+
+    /// The ip of the client, taken from the real ip header only if a trusted proxy sent it.
+    /// Every proxy appends the address it got the request from, so the header is read from
+    /// the right and the first hop which is not a trusted proxy is the client.
+    pub fn real_ip(&self, peer: IpAddr, header: Option<&str>) -> IpAddr {
+        let peer = canonical_ip(peer);
+        let header = match header {
+            Some(header) if self.is_trusted_proxy(peer) => header,
+            _ => return peer,
+        };
+        let mut client = peer;
+        for hop in header.rsplit(',') {
+            match hop.trim().parse::<IpAddr>() {
+                Ok(ip) => {
+                    client = canonical_ip(ip);
+                    if !self.is_trusted_proxy(client) {
+                        break;
+                    }
+                }
+                // anything left of a malformed hop may be made up
+                Err(_) => break,
+            }
+        }
+        client
+    }
+
+    fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+
+    pub fn global(&self) -> Option<&Ratelimiter> {
+        self.global.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn limits(yaml: &str) -> Arc<RateLimits> {
+        let config: Ratelimit = serde_yaml::from_str(yaml).unwrap();
+        RateLimits::from_config(Some(&config)).unwrap()
+    }
+
+    #[test]
+    fn ip_nets_contain_their_addresses() {
+        let cases = [
+            ("10.0.0.0/8", "10.255.1.2", true),
+            ("10.0.0.0/8", "11.0.0.1", false),
+            ("10.0.0.1", "10.0.0.1", true),
+            ("10.0.0.1", "10.0.0.2", false),
+            ("0.0.0.0/0", "192.168.1.1", true),
+            ("10.0.0.0/8", "::ffff:10.1.1.1", true),
+            ("10.0.0.0/8", "::1", false),
+            ("fd00::/8", "fd12::1", true),
+            ("fd00::/8", "fe80::1", false),
+            ("::/0", "2001:db8::1", true),
+        ];
+        for (net, addr, contained) in cases {
+            assert_eq!(IpNet::parse(net).unwrap().contains(ip(addr)), contained, "{} {}", net, addr);
+        }
+        for net in ["10.0.0.0/33", "::/129", "10.0.0", "10.0.0.0/x"] {
+            assert!(IpNet::parse(net).is_err(), "{}", net);
+        }
+    }
+
+    #[test]
+    fn buckets_allow_their_burst_and_refill() {
+        let limiter = KeyedLimiter::new(&Rate { rate: 10.0, burst: Some(2) });
+        assert!(limiter.try_acquire("a").is_ok());
+        assert!(limiter.try_acquire("a").is_ok());
+        let wait = limiter.try_acquire("a").unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_millis(100));
+        assert!(limiter.check("a").is_err());
+        // other keys have their own bucket
+        assert!(limiter.try_acquire("b").is_ok());
+        limiter.refund("a");
+        assert!(limiter.check("a").is_ok());
+        assert!(limiter.try_acquire("a").is_ok());
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(limiter.try_acquire("a").is_ok());
+    }
+
+    #[tokio::test]
+    async fn rejected_requests_take_no_token() {
+        let limits = limits("
+enable: true
+max_rate: 2
+per_ip: { connect: { rate: 0.001, burst: 2 } }
+per_peer: { connect: { rate: 0.001, burst: 1 } }
+");
+        let client = ip("192.0.2.1");
+        assert!(limits.check_connect(client, "peer01").is_ok());
+        // over the peer limit, neither the ip nor the server pays for it
+        for _ in 0..5 {
+            assert!(limits.check_connect(client, "peer01").is_err());
+        }
+        assert!(limits.check_connect(client, "peer02").is_ok());
+        assert!(limits.check_connect(client, "peer03").is_err());
+        assert!(limits.check_connect(ip("192.0.2.2"), "peer04").is_err());
+    }
+
+    #[tokio::test]
+    async fn requests_rejected_by_the_server_limit_keep_their_keyed_tokens() {
+        let limits = limits("
+enable: true
+max_rate: 1
+per_ip: { connect: { rate: 0.001, burst: 2 } }
+per_peer: { connect: { rate: 0.001, burst: 1 } }
+");
+        let client = ip("192.0.2.1");
+        assert!(limits.check_connect(client, "peer01").is_ok());
+        assert!(limits.check_connect(client, "peer02").is_err());
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        // the ip and the peer id got their tokens back
+        assert!(limits.check_connect(client, "peer02").is_ok());
+    }
+
+    #[tokio::test]
+    async fn real_ip_is_only_taken_from_trusted_proxies() {
+        let limits = limits("
+enable: true
+real_ip_header: X-Forwarded-For
+trusted_proxies: [10.0.0.0/8]
+");
+        let proxy = ip("10.0.0.1");
+        let cases = [
+            // the header of an untrusted peer is ignored
+            (ip("192.0.2.9"), Some("127.0.0.1"), "192.0.2.9"),
+            (proxy, None, "10.0.0.1"),
+            (proxy, Some("192.0.2.1"), "192.0.2.1"),
+            // the client put made up hops in front of the one the proxy appended
+            (proxy, Some("127.0.0.1, 192.0.2.1"), "192.0.2.1"),
+            // hops of trusted proxies are skipped
+            (proxy, Some("192.0.2.1, 10.0.0.2"), "192.0.2.1"),
+            (proxy, Some("::ffff:192.0.2.1"), "192.0.2.1"),
+            // nothing left of a malformed hop is trusted
+            (proxy, Some("192.0.2.1, junk, 10.0.0.2"), "10.0.0.2"),
+            (proxy, Some("junk"), "10.0.0.1"),
+        ];
+        for (peer, header, client) in cases {
+            assert_eq!(limits.real_ip(peer, header), ip(client), "{:?}", header);
+        }
+    }
+}
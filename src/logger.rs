@@ -1,19 +1,30 @@
 use tklog::{
-    LEVEL, LOG,
+    LEVEL,
     Format,MODE,
 };
+use tklog::sync::Log as SyncLog;
 use crate::config::Log;
 use crate::config::LogLevel;
 
-pub fn init(log_config: Log) {
-    let filename = format!("{:}/signalhub.log", log_config.logger_dir);
-    let level = match log_config.logger_level {
+// the logger behind the log macros, the LOG const would hand out a new handle on every use
+static LOG: SyncLog = SyncLog;
+
+fn to_level(level: &LogLevel) -> LEVEL {
+    match level {
         LogLevel::DEBUG => { LEVEL::Debug }
         LogLevel::INFO => { LEVEL::Info }
         LogLevel::WARN => { LEVEL::Warn }
         LogLevel::ERROR => { LEVEL::Error }
         LogLevel::FATAL => { LEVEL::Fatal }
-    };
+    }
+}
+
+pub fn set_level(level: &LogLevel) {
+    LOG.set_level(to_level(level));
+}
+
+pub fn init(log_config: Log) {
+    let filename = format!("{:}/signalhub.log", log_config.logger_dir);
     if log_config.writers == "file" {
         LOG.set_console(false);
         if log_config.log_rotate_date > 0 {
@@ -23,7 +34,7 @@ pub fn init(log_config: Log) {
             LOG.set_cutmode_by_size(filename.as_str(), log_config.log_rotate_size*1024*1024, log_config.log_rotate_date, true);
         }
     }
-    LOG.set_level(level)
-        .set_format(Format::LevelFlag | Format::Time)
+    set_level(&log_config.logger_level);
+    LOG.set_format(Format::LevelFlag | Format::Time)
         .set_formatter("{level} {time} {message}\n");
 }
\ No newline at end of file
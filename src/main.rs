@@ -7,32 +7,59 @@ mod utils;
 mod common;
 mod stats;
 mod handler;
+mod cluster;
+mod registry;
+mod filter;
+mod offline;
+mod reload;
+mod tls;
+mod metrics;
+mod sampler;
+mod limiter;
+mod close_code;
+mod jwt;
+mod origin;
+mod tenant;
+#[cfg(test)]
+mod testing;
 
 use std::fmt::{Debug};
 use std::str;
 use std::string::String;
 use std::net::{Ipv6Addr, SocketAddr};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use bpaf::Bpaf;
 use tklog::{warn};
 use tokio::task;
 use crate::hub::Hub;
 use http::Method;
 use crate::utils::{get_version_num};
-use tower_http::cors::{Any, CorsLayer};
+use tower_http::cors::{AllowOrigin, CorsLayer};
 use axum::{Router};
-use axum::routing::get;
-use crate::config::{Config, Port, Security, Tls, TlsItem};
+use axum::routing::{get, post};
+use crate::config::{Config, Port, Security};
 use futures::future;
 use axum_server::tls_rustls::RustlsConfig;
-use crate::stats::{get_count, get_info, get_profile, get_version};
+use crate::stats::{get_count, get_info, get_metrics, get_profile, get_version, reload_config};
 use local_ip_address::local_ip;
 use crate::handler::{handle_http_or_websocket, handle_post};
-use tower_http::{services::{ServeFile}};
-use ratelimit::Ratelimiter;
+use crate::limiter::RateLimits;
+use crate::jwt::JwtVerifier;
+use crate::origin::OriginPolicy;
+use crate::tenant::Tenants;
 use std::time::Duration;
+use crate::cluster::Cluster;
+use crate::filter::SignalFilter;
+use crate::offline::OfflineBuffer;
+use tokio::sync::watch;
+use tokio::signal::unix::{signal, SignalKind};
+use axum_server::Handle;
+use crate::reload::{log_report, Reloader, Shared};
+use crate::tls::CertWatcher;
+use crate::sampler::Sampler;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
+const DEFAULT_DRAIN_TIMEOUT: u64 = 10;
 
 #[derive(Debug, Clone, Bpaf)]
 #[bpaf(options)]
@@ -49,15 +76,27 @@ pub struct Arguments {
 pub struct AppState {
     pub hub: Hub,
     pub version_number: i32,
+    pub auth: Shared<Auth>,
+    pub ratelimit: Shared<Option<Arc<RateLimits>>>,
+    pub origin: Shared<Option<Arc<OriginPolicy>>>,
+    pub tenants: Shared<Option<Arc<Tenants>>>,
+}
+
+/// The security section and the jwt verifier built from it, a reload swaps both at once.
+#[derive(Clone, Default)]
+pub struct Auth {
     pub security: Option<Security>,
-    pub ratelimit: Option<Arc<Ratelimiter>>,
+    pub jwt: Option<Arc<JwtVerifier>>,
 }
 
 #[derive(Clone)]
 pub struct ConfigState {
     pub hub: Hub,
-    pub config: Config,
-    pub local_ip: String
+    pub config: Shared<Config>,
+    pub local_ip: String,
+    pub reloader: Reloader,
+    pub ratelimit: Shared<Option<Arc<RateLimits>>>,
+    pub sampler: Sampler,
 }
 
 #[tokio::main]
@@ -66,32 +105,67 @@ async fn main() {
     let opts = arguments().run();
     // println!("path {:}", opts.path);
     warn!("version", VERSION);
-    let config = config::parse(opts.path).expect("parse config failed");
-    let ratelimiter: Option<Arc<Ratelimiter>>  = match config.ratelimit {
-        None => { None }
-        Some(ref r) => {
-            match r.enable {
-                true => {Some(Arc::new(Ratelimiter::builder(r.max_rate, Duration::from_secs(1))
-                    .max_tokens(r.max_rate)
-                    .initial_available(r.max_rate)
-                    .build()
-                    .unwrap()))
-                }
-                false => {None}
-            }
-        }
+    let config = config::parse(opts.path.as_str()).expect("parse config failed");
+    let ratelimiter = Arc::new(RwLock::new(RateLimits::from_config(config.ratelimit.as_ref())));
+    let origin = Arc::new(RwLock::new(OriginPolicy::from_config(config.origin.as_ref())));
+    let tenants = Arc::new(RwLock::new(Tenants::from_config(config.tenants.as_ref())));
+    let auth = Arc::new(RwLock::new(Auth {
+        security: config.security.clone(),
+        jwt: JwtVerifier::from_security(config.security.as_ref()).expect("load jwt keys failed"),
+    }));
+    let local_ip = local_ip().unwrap().to_string();
+    let cluster = match config.cluster {
+        Some(ref c) if c.enable => Some(Cluster::new(c, local_ip.as_str())),
+        _ => None,
     };
+    let connection_options = config.connection_options().expect("invalid connection config");
+    warn!("connection options", format!("{:?}", connection_options));
+    let hub = Hub::new(connection_options, SignalFilter::new(config.filter.as_ref()),
+                       OfflineBuffer::from_config(config.offline.as_ref()), cluster.clone());
+    if let Some(cluster) = cluster {
+        warn!("cluster mode enabled, node", cluster.node_id());
+        cluster.start(hub.clone());
+    }
     let app_state = AppState {
-        hub: Hub::new(),
+        hub,
         version_number: get_version_num(VERSION),
-        security: config.security.clone(),
-        ratelimit: ratelimiter,
+        auth,
+        ratelimit: ratelimiter.clone(),
+        origin: origin.clone(),
+        tenants,
     };
+    let hub = app_state.hub.clone();
+    let mut tls = Vec::new();
+    for (port, items) in config.tls_ports() {
+        let rustls = tls::load(&items).expect("load tls certs failed");
+        tls.push((port, rustls));
+    }
+    let shared_config = Arc::new(RwLock::new(config.clone()));
+    let reloader = Reloader::new(opts.path.clone(), shared_config.clone(), &app_state, tls.clone());
+    if !tls.is_empty() && config.cert_watch.as_ref().map(|w| w.enable).unwrap_or(true) {
+        CertWatcher::new(shared_config.clone(), tls.clone(), config.cert_watch.as_ref()).start();
+    }
     let config_state = ConfigState {
         hub: app_state.hub.clone(),
-        config: config.clone(),
-        local_ip: local_ip().unwrap().to_string(),
+        config: shared_config,
+        local_ip,
+        reloader: reloader.clone(),
+        ratelimit: ratelimiter,
+        sampler: Sampler::start(),
     };
+    tokio::spawn(async move {
+        let mut sighup = signal(SignalKind::hangup()).expect("install SIGHUP handler failed");
+        while sighup.recv().await.is_some() {
+            match reloader.reload().await {
+                Ok(report) => log_report(&report),
+                Err(e) => warn!("reload config failed, keep current config", e),
+            }
+        }
+    });
+    let drain_timeout = Duration::from_secs(config.shutdown.as_ref()
+        .and_then(|s| s.drain_timeout)
+        .unwrap_or(DEFAULT_DRAIN_TIMEOUT));
+    let (stop_tx, stop_rx) = watch::channel(false);
     logger::init(config.log);
     let app = Router::new()
         // .layer(cors_layer)
@@ -99,9 +173,18 @@ async fn main() {
         .route("/count", get(get_count).with_state(config_state.clone()))
         .route("/version", get(get_version).with_state(config_state.clone()))
         .route("/info", get(get_info).with_state(config_state.clone()))
+        .route("/metrics", get(get_metrics).with_state(config_state.clone()))
         .route("/profile", get(get_profile).with_state(config_state.clone()))
-        .route_service("/flame.svg", ServeFile::new("flamegraph.svg"))
-        .layer(CorsLayer::new().allow_origin(Any).allow_methods([Method::GET, Method::POST]));
+        .route("/reload", post(reload_config).with_state(config_state.clone()))
+        .layer(CorsLayer::new()
+            // looked up per request, so a reload applies to the next preflight
+            .allow_origin(AllowOrigin::predicate(move |value, _| {
+                match origin.read().unwrap().as_ref() {
+                    None => true,
+                    Some(policy) => value.to_str().map(|o| policy.is_allowed(Some(o))).unwrap_or(false),
+                }
+            }))
+            .allow_methods([Method::GET, Method::POST]));
 
     let mut server_task = tokio::spawn(async  move {
         // run it
@@ -109,26 +192,17 @@ async fn main() {
         if let Some(port) = config.port {
             match port {
                 Port::Port(port) => {
-                    tasks.push(task::spawn(listen_to_http(port, app.clone())));
+                    tasks.push(task::spawn(listen_to_http(port, app.clone(), stop_rx.clone())));
                 }
                 Port::Ports(ports) => {
                     for port in ports {
-                        tasks.push(task::spawn(listen_to_http(port, app.clone())));
+                        tasks.push(task::spawn(listen_to_http(port, app.clone(), stop_rx.clone())));
                     }
                 }
             }
         }
-        if let Some(tls) = config.tls {
-            match tls {
-                Tls::TlsItem(item) => {
-                    tasks.push(task::spawn(listen_to_https(item, app.clone())));
-                }
-                Tls::TlsItems(items) => {
-                    for item in items {
-                        tasks.push(task::spawn(listen_to_https(item, app.clone())));
-                    }
-                }
-            }
+        for (port, rustls) in tls {
+            tasks.push(task::spawn(listen_to_https(port, rustls, app.clone(), stop_rx.clone(), drain_timeout)));
         }
 
         future::join_all(tasks).await;
@@ -139,7 +213,14 @@ async fn main() {
             warn!("server exited, stopping");
             // sender_task.abort();
         }
-        _ = tokio::signal::ctrl_c() => {
+        _ = shutdown_signal() => {
+            warn!("shutting down, draining connections for", format!("{:?}", drain_timeout));
+            // stop accepting, then tell connected peers to go elsewhere
+            let _ = stop_tx.send(true);
+            hub.shutdown().await;
+            if !hub.wait_drained(drain_timeout).await {
+                warn!("drain timeout, remaining clients", hub.num_client().await);
+            }
             // sender_task.abort();
             server_task.abort();
         }
@@ -147,25 +228,43 @@ async fn main() {
 
 }
 
-async fn listen_to_http(port: u16, app: Router) -> std::io::Result<()> {
+async fn shutdown_signal() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("install SIGTERM handler failed");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
+    }
+}
+
+async fn wait_stop(mut stop: watch::Receiver<bool>) {
+    let _ = stop.wait_for(|stopped| *stopped).await;
+}
+
+async fn listen_to_http(port: u16, app: Router, stop: watch::Receiver<bool>) -> std::io::Result<()> {
     let addr = SocketAddr::from((Ipv6Addr::UNSPECIFIED, port));
     let listener = tokio::net::TcpListener::bind(addr)
         .await
         .unwrap();
     warn!("http listening on", listener.local_addr().unwrap());
-    axum::serve(listener, app).await
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(wait_stop(stop))
+        .await
 }
 
-async fn listen_to_https(tls: TlsItem, app: Router) -> std::io::Result<()> {
-    let config = RustlsConfig::from_pem_file(tls.cert, tls.key)
-        .await
-        .unwrap();
+async fn listen_to_https(port: u16, config: RustlsConfig, app: Router, stop: watch::Receiver<bool>, drain_timeout: Duration) -> std::io::Result<()> {
     // run https server
     // let addr = SocketAddr::from(([127, 0, 0, 1], tls.port));
-    let addr = SocketAddr::from((Ipv6Addr::UNSPECIFIED, tls.port));
+    let addr = SocketAddr::from((Ipv6Addr::UNSPECIFIED, port));
     warn!("https listening on", addr);
+    let handle = Handle::new();
+    let cloned = handle.clone();
+    tokio::spawn(async move {
+        wait_stop(stop).await;
+        cloned.graceful_shutdown(Some(drain_timeout));
+    });
     axum_server::bind_rustls(addr, config)
-        .serve(app.into_make_service())
+        .handle(handle)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
 }
 
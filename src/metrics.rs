@@ -0,0 +1,155 @@
+#![deny(unused_imports)]
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use crate::hub::Hub;
+
+// upper bounds in seconds of the connection lifetime buckets
+const LIFETIME_BUCKETS: [f64; 10] = [1.0, 10.0, 30.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 14400.0, 86400.0];
+const ROUTED_ACTIONS: [&str; 4] = ["signal", "signals", "reject", "ping"];
+
+/// Cumulative histogram with fixed buckets.
+pub struct Histogram {
+    bounds: &'static [f64],
+    buckets: Vec<AtomicU64>,
+    sum_millis: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_millis: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        if let Some(i) = self.bounds.iter().position(|bound| secs <= *bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_millis.fetch_add(value.as_millis() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, bucket) in self.bounds.iter().zip(self.buckets.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, cumulative);
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum_millis.load(Ordering::Relaxed) as f64 / 1000.0);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+/// Counters which are only read by the metrics endpoint.
+pub struct Metrics {
+    // messages received for routing, in the order of ROUTED_ACTIONS
+    routed: [AtomicU64; 4],
+    pub peer_not_found: AtomicU64,
+    pub ratelimit_rejections: AtomicU64,
+    pub auth_failures: AtomicU64,
+    pub origin_rejections: AtomicU64,
+    pub ws_lifetime: Histogram,
+    pub polling_lifetime: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            routed: Default::default(),
+            peer_not_found: AtomicU64::new(0),
+            ratelimit_rejections: AtomicU64::new(0),
+            auth_failures: AtomicU64::new(0),
+            origin_rejections: AtomicU64::new(0),
+            ws_lifetime: Histogram::new(&LIFETIME_BUCKETS),
+            polling_lifetime: Histogram::new(&LIFETIME_BUCKETS),
+        }
+    }
+}
+
+impl Metrics {
+
+    pub fn routed(&self, action: &str) {
+        let action = if action == "heartbeat" { "ping" } else { action };
+        if let Some(i) = ROUTED_ACTIONS.iter().position(|a| *a == action) {
+            self.routed[i].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Render the state of the hub in the Prometheus text format.
+pub async fn render(hub: &Hub) -> String {
+    let metrics = hub.metrics();
+    let counters = hub.counters();
+    let (ws, polling) = hub.num_clients_by_kind();
+    let mut out = String::new();
+
+    gauge(&mut out, "cbsignal_clients", "Connected clients by transport.");
+    let _ = writeln!(out, "cbsignal_clients{{transport=\"ws\"}} {}", ws);
+    let _ = writeln!(out, "cbsignal_clients{{transport=\"polling\"}} {}", polling);
+
+    counter(&mut out, "cbsignal_messages_routed_total", "Messages received for routing by action.");
+    for (action, count) in ROUTED_ACTIONS.iter().zip(metrics.routed.iter()) {
+        let _ = writeln!(out, "cbsignal_messages_routed_total{{action=\"{}\"}} {}", action, count.load(Ordering::Relaxed));
+    }
+
+    for (name, help, value) in [
+        ("cbsignal_peer_not_found_total", "Signals whose target peer was not connected.", metrics.peer_not_found.load(Ordering::Relaxed)),
+        ("cbsignal_filter_hits_total", "Signals suppressed by the filter.", hub.filter().hits()),
+        ("cbsignal_ratelimit_rejections_total", "Requests rejected by the rate limiter.", metrics.ratelimit_rejections.load(Ordering::Relaxed)),
+        ("cbsignal_auth_failures_total", "Requests rejected because of an invalid token.", metrics.auth_failures.load(Ordering::Relaxed)),
+        ("cbsignal_origin_rejections_total", "Requests rejected because their origin is not allowed.", metrics.origin_rejections.load(Ordering::Relaxed)),
+        ("cbsignal_slow_consumer_evictions_total", "Websocket clients closed for not reading their messages.", counters.slow_consumer_evictions.load(Ordering::Relaxed)),
+        ("cbsignal_transport_upgrades_total", "Polling clients which switched to websocket.", counters.upgrades.load(Ordering::Relaxed)),
+        ("cbsignal_offline_held_total", "Signals held for a peer which just disconnected.", counters.offline_held.load(Ordering::Relaxed)),
+        ("cbsignal_offline_delivered_total", "Held signals delivered after the peer connected again.", counters.offline_delivered.load(Ordering::Relaxed)),
+    ] {
+        counter(&mut out, name, help);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+
+    if let Some(offline) = hub.offline() {
+        gauge(&mut out, "cbsignal_offline_peers", "Disconnected peers whose signals are held.");
+        let _ = writeln!(out, "cbsignal_offline_peers {}", offline.size());
+    }
+
+    let tenants = hub.tenant_stats();
+    if !tenants.is_empty() {
+        gauge(&mut out, "cbsignal_tenant_clients", "Connected clients by app.");
+        for (app, stats) in tenants.iter() {
+            let _ = writeln!(out, "cbsignal_tenant_clients{{app=\"{}\"}} {}", app, stats.clients.load(Ordering::Relaxed));
+        }
+        counter(&mut out, "cbsignal_tenant_messages_total", "Messages received for routing by app.");
+        for (app, stats) in tenants.iter() {
+            let _ = writeln!(out, "cbsignal_tenant_messages_total{{app=\"{}\"}} {}", app, stats.messages.load(Ordering::Relaxed));
+        }
+    }
+
+    counter(&mut out, "cbsignal_queue_overflows_total", "Messages dropped because the queue of a client was full.");
+    let _ = writeln!(out, "cbsignal_queue_overflows_total{{transport=\"ws\"}} {}", counters.ws_dropped.load(Ordering::Relaxed));
+    let _ = writeln!(out, "cbsignal_queue_overflows_total{{transport=\"polling\"}} {}", counters.polling_dropped.load(Ordering::Relaxed));
+
+    let _ = writeln!(out, "# HELP cbsignal_connection_lifetime_seconds Time from joining to leaving of a client.");
+    let _ = writeln!(out, "# TYPE cbsignal_connection_lifetime_seconds histogram");
+    metrics.ws_lifetime.render(&mut out, "cbsignal_connection_lifetime_seconds", "transport=\"ws\"");
+    metrics.polling_lifetime.render(&mut out, "cbsignal_connection_lifetime_seconds", "transport=\"polling\"");
+    out
+}
+
+fn gauge(out: &mut String, name: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+}
+
+fn counter(out: &mut String, name: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+}
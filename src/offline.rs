@@ -0,0 +1,132 @@
+#![deny(unused_imports)]
+use std::sync::Arc;
+use dashmap::DashMap;
+use tokio::time::{Duration, Instant};
+use crate::common::SignalMsg;
+use crate::config::Offline as OfflineConfig;
+
+const DEFAULT_TTL: u64 = 10;
+pub const DEFAULT_SIZE: usize = 20;
+const DEFAULT_CAPACITY: usize = 10000;
+
+/// Signals held for peers which disconnected moments ago, delivered when they reconnect.
+#[derive(Clone)]
+pub struct OfflineBuffer {
+    // hub key of the disconnected peer -> its held signals
+    entries: Arc<DashMap<String, Held>>,
+    ttl: Duration,
+    size: usize,
+    capacity: usize,
+}
+
+struct Held {
+    app: Option<Arc<str>>,
+    expire_at: Instant,
+    msgs: Vec<Arc<SignalMsg>>,
+}
+
+/// A peer which did not come back in time, with its app and the signals held for it.
+pub type Expired = (String, Option<Arc<str>>, Vec<Arc<SignalMsg>>);
+
+/// Result of holding a signal for a peer.
+pub enum Hold {
+    Held,
+    // the buffer of the peer is full
+    Full,
+    // the peer did not disconnect recently
+    Unknown,
+}
+
+impl OfflineBuffer {
+
+    /// None when buffering is disabled.
+    pub fn from_config(config: Option<&OfflineConfig>) -> Option<Self> {
+        let config = match config {
+            Some(c) if c.enable => c,
+            _ => return None,
+        };
+        Some(Self {
+            entries: Arc::new(DashMap::new()),
+            ttl: Duration::from_secs(config.ttl.unwrap_or(DEFAULT_TTL)),
+            size: config.size.unwrap_or(DEFAULT_SIZE),
+            capacity: config.capacity.unwrap_or(DEFAULT_CAPACITY),
+        })
+    }
+
+    /// Start holding signals for a peer which just disconnected.
+    pub fn left(&self, peer_id: &str, app: Option<Arc<str>>) {
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(peer_id) {
+            return;
+        }
+        // the signals of an expired entry are reported by the next sweep
+        if self.entries.get(peer_id).map(|held| held.expire_at <= Instant::now()).unwrap_or(false) {
+            return;
+        }
+        self.entries.insert(peer_id.to_string(), Held {
+            app,
+            expire_at: Instant::now() + self.ttl,
+            msgs: vec![],
+        });
+    }
+
+    /// Hold a signal for the peer while it is expected back, the check decides whether
+    /// the sender may reach the app of the peer.
+    pub fn hold(&self, peer_id: &str, msg: Arc<SignalMsg>, check: impl FnOnce(Option<&str>) -> bool) -> Hold {
+        match self.entries.get_mut(peer_id) {
+            Some(mut held) if held.expire_at > Instant::now() && check(held.app.as_deref()) => {
+                if held.msgs.len() >= self.size {
+                    return Hold::Full
+                }
+                held.msgs.push(msg);
+                Hold::Held
+            }
+            _ => Hold::Unknown,
+        }
+    }
+
+    /// Signals held for a peer which connected again, an expired entry is left to take_expired
+    /// so its senders learn the signals were not delivered.
+    pub fn take(&self, peer_id: &str) -> Vec<Arc<SignalMsg>> {
+        let now = Instant::now();
+        match self.entries.remove_if(peer_id, |_, held| held.expire_at > now) {
+            Some((_, held)) => held.msgs,
+            None => vec![],
+        }
+    }
+
+    /// Remove the peers which did not come back in time, with the signals held for them.
+    pub fn take_expired(&self) -> Vec<Expired> {
+        let now = Instant::now();
+        let expired: Vec<String> = self.entries.iter()
+            .filter(|entry| entry.expire_at <= now)
+            .map(|entry| entry.key().clone())
+            .collect();
+        expired.into_iter()
+            .filter_map(|peer_id| self.entries.remove_if(&peer_id, |_, held| held.expire_at <= now))
+            .map(|(peer_id, held)| (peer_id, held.app, held.msgs))
+            .collect()
+    }
+
+    pub fn size(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn expired_signals_are_left_for_the_sweep() {
+        let offline = OfflineBuffer::from_config(Some(&OfflineConfig { enable: true, ttl: Some(1), size: None, capacity: None })).unwrap();
+        offline.left("peer000001", None);
+        assert!(matches!(offline.hold("peer000001", Arc::new(SignalMsg::default()), |_| true), Hold::Held));
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        // the peer came back too late and leaves again before the sweep
+        assert!(offline.take("peer000001").is_empty());
+        offline.left("peer000001", None);
+        let expired = offline.take_expired();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].2.len(), 1);
+    }
+}
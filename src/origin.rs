@@ -0,0 +1,200 @@
+#![deny(unused_imports)]
+use std::sync::Arc;
+use anyhow::{anyhow, Result};
+use crate::config::Origin;
+
+/// One entry of the origin allowlist, e.g. `https://example.com`, `https://*.example.com`,
+/// `*.example.com` (any scheme) or `http://localhost:*` (any port).
+#[derive(Debug, Clone)]
+pub struct OriginPattern {
+    scheme: Option<String>,
+    host: String,
+    // `*.example.com` matches every subdomain of example.com but not example.com itself
+    wildcard: bool,
+    // None matches the default port only, Some(None) any port
+    port: Option<Option<u16>>,
+}
+
+impl OriginPattern {
+
+    pub fn parse(s: &str) -> Result<Self> {
+        let invalid = || anyhow!("invalid origin {} in origin.allowlist", s);
+        let s = s.trim().to_ascii_lowercase();
+        let (scheme, rest) = match s.split_once("://") {
+            Some((scheme, rest)) if !scheme.is_empty() => (Some(scheme.to_string()), rest),
+            Some(_) => return Err(invalid()),
+            None => (None, s.as_str()),
+        };
+        let (host, port) = split_port(rest).ok_or_else(invalid)?;
+        let port = match port {
+            None => None,
+            Some("*") => Some(None),
+            Some(port) => match port.parse::<u16>().map_err(|_| invalid())? {
+                port if Some(port) == scheme.as_deref().and_then(default_port) => None,
+                port => Some(Some(port)),
+            },
+        };
+        let (host, wildcard) = match host.strip_prefix("*.") {
+            Some(parent) => (parent, true),
+            None => (host, false),
+        };
+        if host.is_empty() || host.contains('*') || host.contains('/') {
+            return Err(invalid())
+        }
+        Ok(Self { scheme, host: host.to_string(), wildcard, port })
+    }
+
+    fn matches(&self, scheme: &str, host: &str, port: Option<u16>) -> bool {
+        if self.scheme.as_ref().map(|s| s != scheme).unwrap_or(false) {
+            return false
+        }
+        let port_matches = match self.port {
+            None => port.is_none(),
+            Some(None) => true,
+            Some(expected) => port == expected,
+        };
+        let host_matches = if self.wildcard {
+            host.strip_suffix(self.host.as_str())
+                .map(|sub| sub.len() > 1 && sub.ends_with('.'))
+                .unwrap_or(false)
+        } else {
+            host == self.host
+        };
+        port_matches && host_matches
+    }
+}
+
+/// Origins which may open connections from a browser.
+pub struct OriginPolicy {
+    any: bool,
+    patterns: Vec<OriginPattern>,
+    allow_missing: bool,
+}
+
+impl OriginPolicy {
+
+    /// None when every origin is allowed.
+    pub fn from_config(config: Option<&Origin>) -> Option<Arc<Self>> {
+        let config = match config {
+            Some(o) if o.enable => o,
+            _ => return None,
+        };
+        Some(Arc::new(Self {
+            any: config.allowlist.iter().any(|o| o.trim() == "*"),
+            // validated when the config was parsed
+            patterns: config.allowlist.iter()
+                .filter(|o| o.trim() != "*")
+                .filter_map(|o| OriginPattern::parse(o).ok())
+                .collect(),
+            allow_missing: config.allow_missing.unwrap_or(true),
+        }))
+    }
+
+    /// Check the Origin header of a request, None when it was not sent.
+    pub fn is_allowed(&self, origin: Option<&str>) -> bool {
+        let origin = match origin {
+            None => return self.allow_missing,
+            Some(_) if self.any => return true,
+            Some(origin) => origin.trim().to_ascii_lowercase(),
+        };
+        // opaque origins such as `null` never match
+        let (scheme, rest) = match origin.split_once("://") {
+            Some(parts) => parts,
+            None => return false,
+        };
+        let (host, port) = match split_port(rest) {
+            Some((host, None)) => (host, None),
+            Some((host, Some(port))) => match port.parse::<u16>() {
+                Ok(port) if Some(port) == default_port(scheme) => (host, None),
+                Ok(port) => (host, Some(port)),
+                Err(_) => return false,
+            },
+            None => return false,
+        };
+        self.patterns.iter().any(|p| p.matches(scheme, host, port))
+    }
+}
+
+// an explicit default port is the same as none
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "http" | "ws" => Some(80),
+        "https" | "wss" => Some(443),
+        _ => None,
+    }
+}
+
+// split `host[:port]`, ipv6 hosts are in brackets
+fn split_port(s: &str) -> Option<(&str, Option<&str>)> {
+    if s.starts_with('[') {
+        let end = s.find(']')?;
+        let (host, rest) = s.split_at(end + 1);
+        return match rest {
+            "" => Some((host, None)),
+            rest => rest.strip_prefix(':').map(|port| (host, Some(port))),
+        }
+    }
+    Some(match s.rsplit_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (s, None),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allowlist: &[&str], allow_missing: bool) -> Arc<OriginPolicy> {
+        OriginPolicy::from_config(Some(&Origin {
+            enable: true,
+            allowlist: allowlist.iter().map(|o| o.to_string()).collect(),
+            allow_missing: Some(allow_missing),
+        })).unwrap()
+    }
+
+    #[test]
+    fn origins_match_the_allowlist() {
+        let policy = policy(&["https://*.example.com", "http://localhost:*", "https://app.test", "http://[::1]:8080"], false);
+        let cases = [
+            (Some("https://a.example.com"), true),
+            (Some("https://a.b.example.com"), true),
+            (Some("https://example.com"), false),
+            (Some("https://evil-example.com"), false),
+            (Some("https://a.example.com.evil.com"), false),
+            (Some("http://a.example.com"), false),
+            // any port includes the default one
+            (Some("http://localhost"), true),
+            (Some("http://localhost:3000"), true),
+            (Some("https://localhost:3000"), false),
+            (Some("https://app.test"), true),
+            (Some("https://app.test:443"), true),
+            (Some("https://app.test:8443"), false),
+            (Some("HTTPS://APP.TEST"), true),
+            (Some("http://[::1]:8080"), true),
+            (Some("http://[::1]"), false),
+            (Some("http://[::1]:80"), false),
+            (Some("null"), false),
+            (Some(""), false),
+            (None, false),
+        ];
+        for (origin, allowed) in cases {
+            assert_eq!(policy.is_allowed(origin), allowed, "{:?}", origin);
+        }
+    }
+
+    #[test]
+    fn missing_origin_and_any_origin() {
+        assert!(policy(&["https://app.test"], true).is_allowed(None));
+        let any = policy(&["*"], false);
+        assert!(any.is_allowed(Some("https://whatever.test")));
+        assert!(!any.is_allowed(None));
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        for pattern in ["://example.com", "https://", "https://a.*.com", "https://example.com:http", "https://[::1", "https://example.com/path"] {
+            assert!(OriginPattern::parse(pattern).is_err(), "{}", pattern);
+        }
+        assert!(OriginPattern::parse("https://example.com:443").unwrap().port.is_none());
+    }
+}
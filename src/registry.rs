@@ -0,0 +1,355 @@
+#![deny(unused_imports)]
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tklog::{error, warn};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::time::Instant;
+use crate::config::{Registry as RegistryConfig, RegistryBackend};
+
+const DEFAULT_SOCKET_PATH: &str = "/tmp/cbsignal_registry.sock";
+// idle connections kept to the registry server
+const POOL_SIZE: usize = 8;
+// how long to wait for another process which is taking over the socket
+const TAKEOVER_WAIT: Duration = Duration::from_millis(200);
+
+/// Where each peer of the cluster is connected.
+#[async_trait]
+pub trait PeerRegistry: Send + Sync {
+    /// Record that `peer_id` is connected to `node`.
+    async fn register(&self, peer_id: &str, node: &str) -> Result<()>;
+    /// Forget `peer_id`, only if it is still owned by `node`.
+    async fn unregister(&self, peer_id: &str, node: &str) -> Result<()>;
+    /// Returns the node which holds `peer_id`.
+    async fn lookup(&self, peer_id: &str) -> Result<Option<String>>;
+    /// Mark `node` alive and replace the peers it owns with `peers`.
+    async fn heartbeat(&self, node: &str, peers: Vec<String>) -> Result<()>;
+    /// All (peer, node) pairs of live nodes.
+    async fn list(&self) -> Result<Vec<(String, String)>>;
+    /// Forget every peer of `node`, which went away.
+    async fn remove_node(&self, node: &str) -> Result<()>;
+}
+
+/// Registry kept in the memory of this process, filled by the cluster links.
+pub struct MemoryRegistry {
+    node_ttl: Duration,
+    peers: Mutex<HashMap<String, String>>,
+    nodes: Mutex<HashMap<String, Instant>>,
+}
+
+impl MemoryRegistry {
+
+    pub fn new(node_ttl: Duration) -> Self {
+        Self {
+            node_ttl,
+            peers: Mutex::new(HashMap::new()),
+            nodes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn is_alive(&self, node: &str) -> bool {
+        match self.nodes.lock().unwrap().get(node) {
+            None => false,
+            Some(ts) => ts.elapsed() <= self.node_ttl,
+        }
+    }
+
+    fn touch(&self, node: &str) {
+        self.nodes.lock().unwrap().insert(node.to_string(), Instant::now());
+    }
+}
+
+#[async_trait]
+impl PeerRegistry for MemoryRegistry {
+
+    async fn register(&self, peer_id: &str, node: &str) -> Result<()> {
+        self.touch(node);
+        self.peers.lock().unwrap().insert(peer_id.to_string(), node.to_string());
+        Ok(())
+    }
+
+    async fn unregister(&self, peer_id: &str, node: &str) -> Result<()> {
+        let mut peers = self.peers.lock().unwrap();
+        if peers.get(peer_id).map(|owner| owner == node).unwrap_or(false) {
+            peers.remove(peer_id);
+        }
+        Ok(())
+    }
+
+    async fn lookup(&self, peer_id: &str) -> Result<Option<String>> {
+        let node = self.peers.lock().unwrap().get(peer_id).cloned();
+        Ok(node.filter(|node| self.is_alive(node)))
+    }
+
+    async fn heartbeat(&self, node: &str, peers: Vec<String>) -> Result<()> {
+        self.touch(node);
+        let mut map = self.peers.lock().unwrap();
+        map.retain(|_, owner| owner != node);
+        for peer in peers {
+            map.insert(peer, node.to_string());
+        }
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<(String, String)>> {
+        let peers = self.peers.lock().unwrap().iter()
+            .map(|(peer, node)| (peer.clone(), node.clone()))
+            .collect::<Vec<_>>();
+        Ok(peers.into_iter().filter(|(_, node)| self.is_alive(node)).collect())
+    }
+
+    async fn remove_node(&self, node: &str) -> Result<()> {
+        self.peers.lock().unwrap().retain(|_, owner| owner != node);
+        self.nodes.lock().unwrap().remove(node);
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Request {
+    Register { peer: String, node: String },
+    Unregister { peer: String, node: String },
+    Lookup { peer: String },
+    Heartbeat { node: String, peers: Vec<String> },
+    List,
+    RemoveNode { node: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Response {
+    error: Option<String>,
+    node: Option<String>,
+    peers: Option<Vec<(String, String)>>,
+}
+
+/// Registry shared by the processes of one machine over a Unix socket.
+/// The process holding the lock file next to the socket serves a `MemoryRegistry` to the others,
+/// another process takes over serving only once the server is gone and the lock was released.
+pub struct SocketRegistry {
+    path: PathBuf,
+    lock_path: PathBuf,
+    node_ttl: Duration,
+    // idle connections, every call takes one for its round trip so calls run concurrently
+    conns: Mutex<Vec<BufReader<UnixStream>>>,
+    // held for the life of the process once it serves the socket
+    lock: Mutex<Option<File>>,
+}
+
+impl SocketRegistry {
+
+    pub fn new<P: Into<PathBuf>>(path: P, node_ttl: Duration) -> Self {
+        let path = path.into();
+        let mut lock_path = path.clone().into_os_string();
+        lock_path.push(".lock");
+        Self {
+            path,
+            lock_path: lock_path.into(),
+            node_ttl,
+            conns: Mutex::new(Vec::new()),
+            lock: Mutex::new(None),
+        }
+    }
+
+    async fn call(&self, req: Request) -> Result<Response> {
+        let mut line = serde_json::to_string(&req)?;
+        line.push('\n');
+        // retry once on a fresh connection, the server may have been replaced
+        for attempt in 0..2 {
+            let pooled = if attempt == 0 { self.conns.lock().unwrap().pop() } else { None };
+            let mut conn = match pooled {
+                Some(conn) => conn,
+                None => BufReader::new(self.connect().await?),
+            };
+            match round_trip(&mut conn, line.as_bytes()).await {
+                Ok(resp) => {
+                    let mut conns = self.conns.lock().unwrap();
+                    if conns.len() < POOL_SIZE {
+                        conns.push(conn);
+                    }
+                    return match resp.error.clone() {
+                        None => Ok(resp),
+                        Some(e) => Err(anyhow!(e)),
+                    }
+                }
+                Err(e) => {
+                    warn!("registry socket error", e);
+                    // the other idle connections went to the same server
+                    self.conns.lock().unwrap().clear();
+                }
+            }
+        }
+        Err(anyhow!("registry socket {:?} unavailable", self.path))
+    }
+
+    async fn connect(&self) -> Result<UnixStream> {
+        match UnixStream::connect(&self.path).await {
+            Ok(stream) => return Ok(stream),
+            // only a socket nobody listens on may be taken over
+            Err(e) if !matches!(e.kind(), ErrorKind::ConnectionRefused | ErrorKind::NotFound) => return Err(e.into()),
+            Err(_) => {}
+        }
+        if !self.try_lock()? {
+            // another process is taking over
+            tokio::time::sleep(TAKEOVER_WAIT).await;
+            return Ok(UnixStream::connect(&self.path).await?)
+        }
+        let _ = std::fs::remove_file(&self.path);
+        let listener = UnixListener::bind(&self.path)?;
+        warn!("registry serving on", self.path.display());
+        let registry = Arc::new(MemoryRegistry::new(self.node_ttl));
+        tokio::spawn(serve(listener, registry));
+        Ok(UnixStream::connect(&self.path).await?)
+    }
+
+    // The lock is released by the OS when the serving process exits.
+    fn try_lock(&self) -> Result<bool> {
+        let mut lock = self.lock.lock().unwrap();
+        if lock.is_some() {
+            return Ok(true)
+        }
+        let file = OpenOptions::new().create(true).truncate(false).write(true).open(&self.lock_path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = std::io::Error::last_os_error();
+            return match e.kind() {
+                ErrorKind::WouldBlock => Ok(false),
+                _ => Err(e.into()),
+            }
+        }
+        *lock = Some(file);
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl PeerRegistry for SocketRegistry {
+
+    async fn register(&self, peer_id: &str, node: &str) -> Result<()> {
+        self.call(Request::Register { peer: peer_id.to_string(), node: node.to_string() }).await?;
+        Ok(())
+    }
+
+    async fn unregister(&self, peer_id: &str, node: &str) -> Result<()> {
+        self.call(Request::Unregister { peer: peer_id.to_string(), node: node.to_string() }).await?;
+        Ok(())
+    }
+
+    async fn lookup(&self, peer_id: &str) -> Result<Option<String>> {
+        Ok(self.call(Request::Lookup { peer: peer_id.to_string() }).await?.node)
+    }
+
+    async fn heartbeat(&self, node: &str, peers: Vec<String>) -> Result<()> {
+        self.call(Request::Heartbeat { node: node.to_string(), peers }).await?;
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<(String, String)>> {
+        Ok(self.call(Request::List).await?.peers.unwrap_or_default())
+    }
+
+    async fn remove_node(&self, node: &str) -> Result<()> {
+        self.call(Request::RemoveNode { node: node.to_string() }).await?;
+        Ok(())
+    }
+}
+
+async fn round_trip(conn: &mut BufReader<UnixStream>, line: &[u8]) -> Result<Response> {
+    conn.get_mut().write_all(line).await?;
+    let mut resp = String::new();
+    if conn.read_line(&mut resp).await? == 0 {
+        return Err(anyhow!("registry socket closed"))
+    }
+    Ok(serde_json::from_str(&resp)?)
+}
+
+async fn serve(listener: UnixListener, registry: Arc<MemoryRegistry>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("registry accept failed", e);
+                return;
+            }
+        };
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            let (rx, mut tx) = stream.into_split();
+            let mut lines = BufReader::new(rx).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let resp = match serde_json::from_str::<Request>(&line) {
+                    Ok(req) => handle_request(registry.as_ref(), req).await,
+                    Err(e) => Response { error: Some(e.to_string()), ..Response::default() },
+                };
+                let mut out = serde_json::to_string(&resp).unwrap();
+                out.push('\n');
+                if tx.write_all(out.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+async fn handle_request(registry: &MemoryRegistry, req: Request) -> Response {
+    let result = match req {
+        Request::Register { peer, node } => registry.register(&peer, &node).await.map(|_| Response::default()),
+        Request::Unregister { peer, node } => registry.unregister(&peer, &node).await.map(|_| Response::default()),
+        Request::Lookup { peer } => registry.lookup(&peer).await.map(|node| Response { node, ..Response::default() }),
+        Request::Heartbeat { node, peers } => registry.heartbeat(&node, peers).await.map(|_| Response::default()),
+        Request::List => registry.list().await.map(|peers| Response { peers: Some(peers), ..Response::default() }),
+        Request::RemoveNode { node } => registry.remove_node(&node).await.map(|_| Response::default()),
+    };
+    result.unwrap_or_else(|e| Response { error: Some(e.to_string()), ..Response::default() })
+}
+
+pub fn from_config(config: Option<&RegistryConfig>, node_ttl: Duration) -> Arc<dyn PeerRegistry> {
+    match config {
+        Some(RegistryConfig { backend: RegistryBackend::Socket, path }) => {
+            let path = path.clone().unwrap_or(DEFAULT_SOCKET_PATH.to_string());
+            Arc::new(SocketRegistry::new(path, node_ttl))
+        }
+        _ => Arc::new(MemoryRegistry::new(node_ttl)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("cbsignal_registry_{}_{}.sock", name, std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn processes_share_one_server_and_take_over_stale_socket() {
+        let path = socket_path("share");
+        // left behind by a server which crashed
+        let _ = std::fs::remove_file(&path);
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+        let ttl = Duration::from_secs(60);
+        let first = SocketRegistry::new(&path, ttl);
+        let second = SocketRegistry::new(&path, ttl);
+        first.register("peer1", "node1").await.unwrap();
+        let lookups: Vec<_> = (0..16).map(|_| second.lookup("peer1")).collect();
+        for node in futures::future::join_all(lookups).await {
+            assert_eq!(node.unwrap().as_deref(), Some("node1"));
+        }
+        // only the first one serves, the second one did not bind a registry of its own
+        assert!(first.lock.lock().unwrap().is_some());
+        assert!(second.lock.lock().unwrap().is_none());
+
+        second.remove_node("node1").await.unwrap();
+        assert_eq!(first.lookup("peer1").await.unwrap(), None);
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&first.lock_path);
+    }
+}
@@ -0,0 +1,178 @@
+#![deny(unused_imports)]
+use std::fmt::Debug;
+use std::sync::{Arc, RwLock};
+use axum_server::tls_rustls::RustlsConfig;
+use serde::Serialize;
+use tklog::{error, warn};
+use crate::config::{self, Config};
+use crate::limiter::RateLimits;
+use crate::jwt::JwtVerifier;
+use crate::origin::OriginPolicy;
+use crate::tenant::Tenants;
+use crate::{logger, tls, AppState, Auth};
+
+pub type Shared<T> = Arc<RwLock<T>>;
+
+/// What a reload changed.
+#[derive(Serialize, Debug, Default)]
+pub struct ReloadReport {
+    pub applied: Vec<String>,
+    // changed sections which only take effect after a restart
+    pub restart_required: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+/// Re-reads the config file and applies the sections which are safe to change at runtime.
+#[derive(Clone)]
+pub struct Reloader {
+    path: Arc<String>,
+    config: Shared<Config>,
+    auth: Shared<Auth>,
+    ratelimit: Shared<Option<Arc<RateLimits>>>,
+    origin: Shared<Option<Arc<OriginPolicy>>>,
+    tenants: Shared<Option<Arc<Tenants>>>,
+    // tls port -> certificate served on that port
+    tls: Arc<Vec<(u16, RustlsConfig)>>,
+}
+
+impl Reloader {
+
+    /// Sections applied live are swapped in the shared state of the handlers.
+    pub fn new(path: String, config: Shared<Config>, state: &AppState, tls: Vec<(u16, RustlsConfig)>) -> Self {
+        Self {
+            path: Arc::new(path),
+            config,
+            auth: state.auth.clone(),
+            ratelimit: state.ratelimit.clone(),
+            origin: state.origin.clone(),
+            tenants: state.tenants.clone(),
+            tls: Arc::new(tls),
+        }
+    }
+
+    pub async fn reload(&self) -> anyhow::Result<ReloadReport> {
+        let mut new = config::parse(self.path.as_str())?;
+        let old = self.config.read().unwrap().clone();
+        let mut report = ReloadReport::default();
+
+        // jwt keys are loaded again even if the config did not change, a JWKS file may have been rotated
+        match JwtVerifier::from_security(new.security.as_ref()) {
+            Ok(jwt) => {
+                let reloaded = jwt.is_some();
+                let security_changed = changed(&old.security, &new.security);
+                // a request sees either the old or the new keys, never mixed with the other security section
+                {
+                    let mut auth = self.auth.write().unwrap();
+                    auth.jwt = jwt;
+                    if security_changed {
+                        auth.security = new.security.clone();
+                    }
+                }
+                if security_changed {
+                    report.applied.push("security".to_string());
+                } else if reloaded {
+                    report.applied.push("security.jwt".to_string());
+                }
+            }
+            Err(e) => {
+                error!("load jwt keys failed, keep current security", e);
+                report.errors.push(format!("security: {}", e));
+                new.security = old.security.clone();
+            }
+        }
+        if changed(&old.ratelimit, &new.ratelimit) {
+            *self.ratelimit.write().unwrap() = RateLimits::from_config(new.ratelimit.as_ref());
+            report.applied.push("ratelimit".to_string());
+        }
+        if changed(&old.origin, &new.origin) {
+            *self.origin.write().unwrap() = OriginPolicy::from_config(new.origin.as_ref());
+            report.applied.push("origin".to_string());
+        }
+        // connected clients keep their app, limits of an app start over
+        if changed(&old.tenants, &new.tenants) {
+            *self.tenants.write().unwrap() = Tenants::from_config(new.tenants.as_ref());
+            report.applied.push("tenants".to_string());
+        }
+        if changed(&old.stats, &new.stats) {
+            report.applied.push("stats".to_string());
+        }
+        if changed(&old.log.logger_level, &new.log.logger_level) {
+            logger::set_level(&new.log.logger_level);
+            report.applied.push("log.logger_level".to_string());
+        }
+        if old.log.writers != new.log.writers || old.log.logger_dir != new.log.logger_dir
+            || old.log.log_rotate_date != new.log.log_rotate_date || old.log.log_rotate_size != new.log.log_rotate_size {
+            report.restart_required.push("log".to_string());
+        }
+        self.reload_tls(&new, &mut report);
+        for (name, changed) in [
+            ("port", changed(&old.port, &new.port)),
+            ("cluster", changed(&old.cluster, &new.cluster)),
+            ("connection", changed(&old.connection, &new.connection)),
+            ("filter", changed(&old.filter, &new.filter)),
+            ("offline", changed(&old.offline, &new.offline)),
+            ("shutdown", changed(&old.shutdown, &new.shutdown)),
+            ("cert_watch", changed(&old.cert_watch, &new.cert_watch)),
+            ("compression", changed(&old.compression, &new.compression)),
+        ] {
+            if changed {
+                report.restart_required.push(name.to_string());
+            }
+        }
+        // keep what is actually running, so pending changes are reported until the restart
+        let level = new.log.logger_level.clone();
+        new.log = old.log;
+        new.log.logger_level = level;
+        new.port = old.port;
+        new.cluster = old.cluster;
+        new.connection = old.connection;
+        new.filter = old.filter;
+        new.offline = old.offline;
+        new.shutdown = old.shutdown;
+        new.cert_watch = old.cert_watch;
+        new.compression = old.compression;
+        *self.config.write().unwrap() = new;
+        Ok(report)
+    }
+
+    // Certificates are re-read for every port which is already listening,
+    // a port whose certs fail to load keeps the ones in use.
+    fn reload_tls(&self, new: &Config, report: &mut ReloadReport) {
+        let tls_ports = new.tls_ports();
+        let ports: Vec<u16> = tls_ports.iter().map(|(port, _)| *port).collect();
+        let mut listening: Vec<u16> = self.tls.iter().map(|(port, _)| *port).collect();
+        listening.sort();
+        if ports != listening {
+            report.restart_required.push("tls".to_string());
+        }
+        let mut reloaded = false;
+        for (port, items) in tls_ports {
+            if let Some((_, rustls)) = self.tls.iter().find(|(p, _)| *p == port) {
+                match tls::reload(rustls, &items) {
+                    Ok(_) => reloaded = true,
+                    Err(e) => {
+                        error!("reload certs of port", port, "failed", e);
+                        report.errors.push(format!("tls {}: {}", port, e));
+                    }
+                }
+            }
+        }
+        if reloaded {
+            report.applied.push("tls".to_string());
+        }
+    }
+}
+
+pub fn log_report(report: &ReloadReport) {
+    warn!("config reloaded, applied", format!("{:?}", report.applied));
+    if !report.restart_required.is_empty() {
+        warn!("config changes need a restart", format!("{:?}", report.restart_required));
+    }
+    if !report.errors.is_empty() {
+        error!("config reload errors", format!("{:?}", report.errors));
+    }
+}
+
+fn changed<T: Debug>(old: &T, new: &T) -> bool {
+    format!("{:?}", old) != format!("{:?}", new)
+}
@@ -0,0 +1,112 @@
+#![deny(unused_imports)]
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use systemstat::{saturating_sub_bytes, Platform, System};
+use tokio::time::Instant;
+
+// window over which cpu usage is measured, and how often the sample is refreshed
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Resource usage of the process and of the host, as of the last sample.
+#[derive(Default, Clone, Debug)]
+pub struct Sample {
+    pub cpu_usage: i32,
+    pub rss: Option<u64>,
+    pub fds: Option<usize>,
+    pub threads: Option<usize>,
+    pub tokio_workers: usize,
+    // only counted by builds with `--cfg tokio_unstable`
+    pub tokio_tasks: Option<usize>,
+    pub host_cpu_usage: i32,
+    pub host_memory_used: u64,
+    pub host_memory_total: u64,
+}
+
+/// Samples resource usage in the background so readers never wait for a measurement.
+#[derive(Clone)]
+pub struct Sampler {
+    started_at: Instant,
+    last: Arc<RwLock<Sample>>,
+}
+
+impl Sampler {
+
+    pub fn start() -> Self {
+        let s = Self {
+            started_at: Instant::now(),
+            last: Arc::new(RwLock::new(Sample::default())),
+        };
+        let last = s.last.clone();
+        tokio::spawn(async move {
+            let sys = System::new();
+            loop {
+                let host_cpu = sys.cpu_load_aggregate().ok();
+                let cpu_time = process_cpu_time();
+                let start = Instant::now();
+                tokio::time::sleep(SAMPLE_INTERVAL).await;
+                let mut sample = Sample {
+                    tokio_workers: tokio::runtime::Handle::current().metrics().num_workers(),
+                    tokio_tasks: tokio_tasks(),
+                    rss: process_rss(),
+                    fds: std::fs::read_dir("/proc/self/fd").ok().map(|dir| dir.count()),
+                    threads: process_threads(),
+                    ..Sample::default()
+                };
+                if let (Some(before), Some(after)) = (cpu_time, process_cpu_time()) {
+                    sample.cpu_usage = ((after - before).as_secs_f64() / start.elapsed().as_secs_f64() * 100.0) as i32;
+                }
+                if let Some(Ok(cpu)) = host_cpu.map(|cpu| cpu.done()) {
+                    sample.host_cpu_usage = ((1f32 - cpu.idle) * 100.0) as i32;
+                }
+                if let Ok(mem) = sys.memory() {
+                    sample.host_memory_used = saturating_sub_bytes(mem.total, mem.free).as_u64();
+                    sample.host_memory_total = mem.total.as_u64();
+                }
+                *last.write().unwrap() = sample;
+            }
+        });
+        s
+    }
+
+    pub fn last(&self) -> Sample {
+        self.last.read().unwrap().clone()
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+}
+
+// user and system cpu time of the whole process
+fn process_cpu_time() -> Option<Duration> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return None
+    }
+    let micros = |t: libc::timeval| t.tv_sec as u64 * 1_000_000 + t.tv_usec as u64;
+    Some(Duration::from_micros(micros(usage.ru_utime) + micros(usage.ru_stime)))
+}
+
+fn process_rss() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * page_size as u64)
+}
+
+fn process_threads() -> Option<usize> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status.lines()
+        .find_map(|line| line.strip_prefix("Threads:"))
+        .and_then(|n| n.trim().parse().ok())
+}
+
+#[cfg(tokio_unstable)]
+fn tokio_tasks() -> Option<usize> {
+    Some(tokio::runtime::Handle::current().metrics().active_tasks_count())
+}
+
+#[cfg(not(tokio_unstable))]
+fn tokio_tasks() -> Option<usize> {
+    None
+}
@@ -1,13 +1,15 @@
 #![deny(unused_imports)]
 
-use std::fs::File;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 use axum::extract::{Query, State};
 use crate::{ConfigState};
 use crate::common::{ApiError, ApiResponse, ParseError};
-use crate::config::{Stats, Tls};
+use crate::config::Stats;
+use crate::reload::log_report;
+use crate::{metrics, tls};
+use pprof::protos::Message;
 use serde::{Deserialize, Serialize};
-use systemstat::{System, Platform, saturating_sub_bytes};
 use tklog::{error, warn};
 use x509_parser::prelude::*;
 
@@ -16,63 +18,104 @@ pub struct StatsParams {
     token: Option<String>,
 }
 
+#[derive(serde::Deserialize, Clone)]
+pub struct ProfileParams {
+    token: Option<String>,
+    seconds: Option<u64>,
+    frequency: Option<i32>,
+    format: Option<String>,     // svg (flame graph) or pprof (protobuf)
+}
+
 const VERSION: &str = env!("CARGO_PKG_VERSION");
+const DEFAULT_PROFILE_SECONDS: u64 = 30;
+const MAX_PROFILE_SECONDS: u64 = 300;
+const DEFAULT_PROFILE_FREQUENCY: i32 = 1000;
+const MAX_PROFILE_FREQUENCY: i32 = 10000;
+// peers listed in /info by the messages their full polling queue lost
+const TOP_DROPPED_PEERS: usize = 20;
+
+// only one profiler can sample the process at a time
+static PROFILE_RUNNING: AtomicBool = AtomicBool::new(false);
 
 #[serde_with::skip_serializing_none]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Info {
     version: String,
     current_connections: usize,
-    rate_limit: usize,
+    ws_connections: usize,
+    polling_connections: usize,
+    cluster_peers: Option<usize>,
+    filter_size: usize,
+    filter_hits: u64,
+    filter_misses: u64,
+    polling_dropped: u64,
+    ws_dropped: u64,
+    dropped_peers: Option<Vec<DroppedInfo>>,
+    slow_consumer_evictions: u64,
+    rate_limit: u64,                    // configured max rate, 0 when disabled
+    rate_limit_available: Option<u64>,  // tokens left in the current interval
     security_enabled: bool,
-    cpu_usage: i32,
+    uptime: u64,
+    cpu_usage: i32,                     // of this process, 100 per busy core
     internal_ip: String,
     // compression_enabled: bool,
-    memory: u64,
+    memory: u64,                        // resident memory of this process
+    fds: Option<usize>,
+    threads: Option<usize>,
+    tokio_workers: usize,
+    tokio_tasks: Option<usize>,
+    host_cpu_usage: i32,
+    host_memory_used: u64,
+    host_memory_total: u64,
     cert_infos: Option<Vec<CertInfo>>,
+    tenants: Option<Vec<TenantInfo>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct TenantInfo {
+    app: String,
+    clients: usize,
+    messages: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct DroppedInfo {
+    peer_id: String,
+    dropped: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct CertInfo {
     name: String,
     expire_at: String,
+    port: u16,
+    server_names: Vec<String>,
+    default: bool,
 }
 
 pub async fn get_count(State(state): State<ConfigState>, Query(params): Query<StatsParams>) -> anyhow::Result<ApiResponse, ApiError> {
-    if !check_token(params.token, state.config.stats) {
+    if !check_token(params.token, stats_config(&state)) {
         return Err(ApiError::Unauthorised)
     }
     Ok(ApiResponse::Count(state.hub.num_client().await.to_string()))
 }
 
 pub async fn get_version(State(state): State<ConfigState>, Query(params): Query<StatsParams>) -> anyhow::Result<ApiResponse, ApiError> {
-    if !check_token(params.token, state.config.stats) {
+    if !check_token(params.token, stats_config(&state)) {
         return Err(ApiError::Unauthorised)
     }
     Ok(ApiResponse::Count(VERSION.to_string()))
 }
 
 pub async fn get_info(State(state): State<ConfigState>, Query(params): Query<StatsParams>) -> anyhow::Result<ApiResponse, ApiError> {
-    if !check_token(params.token, state.config.stats) {
+    if !check_token(params.token, stats_config(&state)) {
         return Err(ApiError::Unauthorised)
     }
-    let sys = System::new();
-    let used_memory = match sys.memory() {
-        Ok(mem) => saturating_sub_bytes(mem.total, mem.free).as_u64(),
-        Err(x) => 0
-    };
-    let cpu = match sys.cpu_load_aggregate() {
-        Ok(cpu)=> {
-            tokio::time::sleep(Duration::from_secs(2)).await;
-            if let Ok(c) = cpu.done() {
-                ((1f32-c.idle) * 100.0) as i32
-            } else {
-                0
-            }
-        },
-        Err(x) => 0
-    };
-    let security_enabled = match state.config.security {
+    let config = state.config.read().unwrap().clone();
+    let sample = state.sampler.last();
+    let limiter = state.ratelimit.read().unwrap().clone();
+    let (ws_connections, polling_connections) = state.hub.num_clients_by_kind();
+    let security_enabled = match config.security.as_ref() {
         None => false,
         Some(security) => security.enable,
     };
@@ -81,55 +124,157 @@ pub async fn get_info(State(state): State<ConfigState>, Query(params): Query<Sta
     //     Some(compression) => compression.enable,
     // };
     let mut cert_infos = Vec::new();
-    if let Some(tls) = state.config.tls {
-        match tls {
-            Tls::TlsItem(item) => {
-                if let Ok(info) = parse_cert(item.cert.as_str()) {
-                    cert_infos.push(info);
-                }
-            }
-            Tls::TlsItems(items) => {
-                for item in items {
-                    if let Ok(info) = parse_cert(item.cert.as_str()) {
-                        cert_infos.push(info);
-                    }
-                }
+    for (port, items) in config.tls_ports() {
+        for item in items.iter() {
+            if let Ok(mut info) = parse_cert(item.cert.as_str()) {
+                info.port = port;
+                info.server_names = tls::server_names(item).unwrap_or_default();
+                info.default = tls::is_default(item, &items);
+                cert_infos.push(info);
             }
         }
     }
     let mut info = Info{
         version: VERSION.to_string(),
-        current_connections: state.hub.num_client().await,
-        rate_limit: 0,
+        current_connections: ws_connections + polling_connections,
+        ws_connections,
+        polling_connections,
+        cluster_peers: state.hub.num_remote_peers().await,
+        filter_size: state.hub.filter().size(),
+        filter_hits: state.hub.filter().hits(),
+        filter_misses: state.hub.filter().misses(),
+        polling_dropped: state.hub.counters().polling_dropped.load(Ordering::Relaxed),
+        ws_dropped: state.hub.counters().ws_dropped.load(Ordering::Relaxed),
+        dropped_peers: None,
+        slow_consumer_evictions: state.hub.counters().slow_consumer_evictions.load(Ordering::Relaxed),
+        rate_limit: limiter.as_ref().and_then(|l| l.global()).map(|l| l.max_tokens()).unwrap_or(0),
+        rate_limit_available: limiter.as_ref().and_then(|l| l.global()).map(|l| l.available()),
         security_enabled,
-        cpu_usage: cpu,
+        uptime: state.sampler.uptime().as_secs(),
+        cpu_usage: sample.cpu_usage,
         internal_ip: state.local_ip,
         // compression_enabled,
-        memory: used_memory,
+        memory: sample.rss.unwrap_or(0),
+        fds: sample.fds,
+        threads: sample.threads,
+        tokio_workers: sample.tokio_workers,
+        tokio_tasks: sample.tokio_tasks,
+        host_cpu_usage: sample.host_cpu_usage,
+        host_memory_used: sample.host_memory_used,
+        host_memory_total: sample.host_memory_total,
         cert_infos: None,
+        tenants: None,
     };
+    let tenants: Vec<TenantInfo> = state.hub.tenant_stats().into_iter().map(|(app, stats)| TenantInfo {
+        app: app.to_string(),
+        clients: stats.clients.load(Ordering::Relaxed),
+        messages: stats.messages.load(Ordering::Relaxed),
+    }).collect();
+    if !tenants.is_empty() {
+        info.tenants = Some(tenants);
+    }
+    let dropped_peers: Vec<DroppedInfo> = state.hub.dropped_by_peer(TOP_DROPPED_PEERS).into_iter()
+        .map(|(peer_id, dropped)| DroppedInfo { peer_id, dropped })
+        .collect();
+    if !dropped_peers.is_empty() {
+        info.dropped_peers = Some(dropped_peers);
+    }
     if cert_infos.len() > 0 {
         info.cert_infos = Some(cert_infos);
     }
-    Ok(ApiResponse::Info(info))
+    Ok(ApiResponse::Info(Box::new(info)))
 }
 
-pub async fn get_profile(State(state): State<ConfigState>, Query(params): Query<StatsParams>) -> anyhow::Result<ApiResponse, ApiError> {
-    if !check_token(params.token, state.config.stats) {
+pub async fn get_metrics(State(state): State<ConfigState>, Query(params): Query<StatsParams>) -> anyhow::Result<ApiResponse, ApiError> {
+    if !check_token(params.token, stats_config(&state)) {
         return Err(ApiError::Unauthorised)
     }
-    tokio::task::spawn(async move {
-        let mut guard = pprof::ProfilerGuardBuilder::default().frequency(1000)
-            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
-            .build().unwrap();
-        tokio::time::sleep(Duration::from_secs(30)).await;
-        if let Ok(report) = guard.report().build() {
-            let file = File::create("flamegraph.svg").unwrap();
-            report.flamegraph(file).unwrap();
-            warn!("profile done")
-        };
-    });
-    Ok(ApiResponse::OK)
+    Ok(ApiResponse::Metrics(metrics::render(&state.hub).await))
+}
+
+pub async fn get_profile(State(state): State<ConfigState>, Query(params): Query<ProfileParams>) -> anyhow::Result<ApiResponse, ApiError> {
+    if !check_token(params.token, stats_config(&state)) {
+        return Err(ApiError::Unauthorised)
+    }
+    let seconds = params.seconds.unwrap_or(DEFAULT_PROFILE_SECONDS);
+    let frequency = params.frequency.unwrap_or(DEFAULT_PROFILE_FREQUENCY);
+    let pprof_format = match params.format.as_deref() {
+        None | Some("svg") => false,
+        Some("pprof") => true,
+        Some(_) => return Err(ApiError::BadRequest),
+    };
+    if seconds == 0 || seconds > MAX_PROFILE_SECONDS || frequency <= 0 || frequency > MAX_PROFILE_FREQUENCY {
+        return Err(ApiError::BadRequest)
+    }
+    // released when the profile is done or the request is dropped
+    let _running = match ProfileRunning::acquire() {
+        None => return Err(ApiError::CONFLICT),
+        Some(running) => running,
+    };
+    let guard = pprof::ProfilerGuardBuilder::default().frequency(frequency)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(|e| {
+            error!("start profiler failed", e);
+            ApiError::InternalServerError
+        })?;
+    tokio::time::sleep(Duration::from_secs(seconds)).await;
+    // symbolizing takes a while, keep it off the worker which serves the other tasks
+    let body = tokio::task::block_in_place(|| -> anyhow::Result<Vec<u8>> {
+        let report = guard.report().build()?;
+        let mut body = Vec::new();
+        if pprof_format {
+            report.pprof()?.encode(&mut body)?;
+        } else {
+            report.flamegraph(&mut body)?;
+        }
+        Ok(body)
+    }).map_err(|e| {
+        error!("build profile failed", e);
+        ApiError::InternalServerError
+    })?;
+    warn!("profile done, seconds", seconds, "frequency", frequency);
+    Ok(if pprof_format {
+        ApiResponse::Profile("application/octet-stream", body)
+    } else {
+        ApiResponse::Profile("image/svg+xml", body)
+    })
+}
+
+struct ProfileRunning;
+
+impl ProfileRunning {
+    fn acquire() -> Option<Self> {
+        PROFILE_RUNNING.compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire).ok().map(|_| Self)
+    }
+}
+
+impl Drop for ProfileRunning {
+    fn drop(&mut self) {
+        PROFILE_RUNNING.store(false, Ordering::Release);
+    }
+}
+
+pub async fn reload_config(State(state): State<ConfigState>, Query(params): Query<StatsParams>) -> anyhow::Result<ApiResponse, ApiError> {
+    // reloading is an admin operation, never open it without a stats token
+    let stats = stats_config(&state);
+    if stats.as_ref().and_then(|s| s.token.as_ref()).is_none() || !check_token(params.token, stats) {
+        return Err(ApiError::Unauthorised)
+    }
+    match state.reloader.reload().await {
+        Ok(report) => {
+            log_report(&report);
+            Ok(ApiResponse::Reload(report))
+        }
+        Err(e) => {
+            warn!("reload config failed, keep current config", e);
+            Err(ApiError::BadRequest)
+        }
+    }
+}
+
+fn stats_config(state: &ConfigState) -> Option<Stats> {
+    state.config.read().unwrap().stats.clone()
 }
 
 fn check_token(token: Option<String>, stats: Option<Stats>) -> bool {
@@ -146,8 +291,9 @@ fn check_token(token: Option<String>, stats: Option<Stats>) -> bool {
 }
 
 fn parse_cert(file_name: &str) -> anyhow::Result<CertInfo> {
-    let data = std::fs::read(file_name.clone()).expect("Unable to read file");
-    if matches!((data[0], data[1]), (0x30, 0x81..=0x83)) {
+    // a cert being rotated may be missing or empty for a moment
+    let data = std::fs::read(file_name)?;
+    if matches!(data.get(..2), Some([0x30, 0x81..=0x83])) {
         // probably DER
         handle_certificate(&file_name, &data)
     } else {
@@ -174,6 +320,9 @@ fn handle_certificate(file_name: &str, data: &[u8]) -> anyhow::Result<CertInfo>
             Ok(CertInfo{
                 name: x509.subject().to_string().split('=').next_back().unwrap().to_string(),
                 expire_at: x509.validity().not_after.to_datetime().to_string(),
+                port: 0,
+                server_names: vec![],
+                default: false,
             })
         }
         Err(e) => {
@@ -182,4 +331,19 @@ fn handle_certificate(file_name: &str, data: &[u8]) -> anyhow::Result<CertInfo>
             Err(e.into())
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_or_empty_certs_are_errors() {
+        let path = std::env::temp_dir().join(format!("cbsignal_empty_cert_{}.pem", std::process::id()));
+        assert!(parse_cert(path.to_str().unwrap()).is_err());
+        std::fs::write(&path, "").unwrap();
+        assert!(parse_cert(path.to_str().unwrap()).is_err());
+        let _ = std::fs::remove_file(&path);
+        let cert = format!("{}/testdata/tls/a.pem", env!("CARGO_MANIFEST_DIR"));
+        assert_eq!(parse_cert(&cert).unwrap().name, "a.example.com");
+    }
+}
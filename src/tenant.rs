@@ -0,0 +1,105 @@
+#![deny(unused_imports)]
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::config::{self, Security, SecurityMode};
+use crate::limiter::KeyedLimiters;
+use crate::utils::check_token;
+
+/// Separates the app id from the peer id in the keys of the hub.
+pub const SEPARATOR: char = '/';
+
+const DEFAULT_MAX_TIMESTAMP_AGE: u64 = 3600;
+
+/// The key of a peer in the hub, peers of the default namespace keep their plain id.
+pub fn peer_key(app: Option<&str>, peer_id: &str) -> String {
+    match app {
+        None => peer_id.to_string(),
+        Some(app) => format!("{}{}{}", app, SEPARATOR, peer_id),
+    }
+}
+
+/// Whether clients of the two apps may exchange messages, the default namespace is one more app.
+/// Keys are not trusted for this, a default peer id may contain the separator while tenants are off.
+pub fn same_app(a: Option<&str>, b: Option<&str>) -> bool {
+    a == b
+}
+
+/// The peer id a client of the app knows the peer with this key by.
+pub fn strip_app<'a>(key: &'a str, app: &str) -> &'a str {
+    key.strip_prefix(app)
+        .and_then(|rest| rest.strip_prefix(SEPARATOR))
+        .unwrap_or(key)
+}
+
+/// An app with its own peer namespace, signing keys, limits and connection cap.
+pub struct Tenant {
+    pub id: Arc<str>,
+    security: Security,
+    max_clients: Option<usize>,
+    limits: KeyedLimiters,
+}
+
+impl Tenant {
+
+    fn new(app: &config::App) -> Self {
+        Self {
+            id: Arc::from(app.id.as_str()),
+            security: Security {
+                enable: true,
+                mode: SecurityMode::Token,
+                maxTimeStampAge: app.max_timestamp_age.unwrap_or(DEFAULT_MAX_TIMESTAMP_AGE),
+                token: app.token.clone(),
+                keys: app.keys.clone(),
+                jwt: None,
+            },
+            max_clients: app.max_clients,
+            limits: KeyedLimiters::new(app.ratelimit.as_ref()),
+        }
+    }
+
+    pub fn check_token(&self, peer_id: &str, token: Option<String>) -> bool {
+        check_token(peer_id, token, &self.security)
+    }
+
+    pub fn check_connect(&self) -> Result<(), Duration> {
+        self.limits.check_connect(&self.id)
+    }
+
+    pub fn check_message(&self) -> Result<(), Duration> {
+        self.limits.check_message(&self.id)
+    }
+
+    pub fn max_clients(&self) -> Option<usize> {
+        self.max_clients
+    }
+}
+
+/// Apps sharing this node.
+pub struct Tenants {
+    apps: HashMap<String, Arc<Tenant>>,
+    allow_default: bool,
+}
+
+impl Tenants {
+
+    /// None when multi-tenancy is disabled.
+    pub fn from_config(config: Option<&config::Tenants>) -> Option<Arc<Self>> {
+        let config = match config {
+            Some(t) if t.enable => t,
+            _ => return None,
+        };
+        Some(Arc::new(Self {
+            apps: config.apps.iter().map(|app| (app.id.clone(), Arc::new(Tenant::new(app)))).collect(),
+            allow_default: config.allow_default.unwrap_or(true),
+        }))
+    }
+
+    pub fn get(&self, app: &str) -> Option<Arc<Tenant>> {
+        self.apps.get(app).cloned()
+    }
+
+    pub fn allow_default(&self) -> bool {
+        self.allow_default
+    }
+}
@@ -0,0 +1,45 @@
+//! Helpers shared by the tests of several modules.
+#![deny(unused_imports)]
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, RwLock};
+use tokio::sync::mpsc;
+use crate::AppState;
+use crate::client::{Client, WsSender};
+use crate::config::Connection;
+use crate::filter::SignalFilter;
+use crate::hub::Hub;
+
+pub const PEER: &str = "peer000001";
+
+/// Unix time in seconds, as used in tokens.
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+pub fn new_hub() -> Hub {
+    Hub::new(Connection::default().options().unwrap(), SignalFilter::new(None), None, None)
+}
+
+/// A websocket client of PEER, its queue is not read.
+pub fn ws_client(hub: &Hub) -> Client {
+    let (tx, _) = WsSender::channel(hub.options());
+    Client::new(PEER, tx, hub.options())
+}
+
+/// A polling client of PEER without a pending poll.
+pub fn polling_client(hub: &Hub) -> Client {
+    let (tx, _) = mpsc::channel(1);
+    Client::new_poll(PEER, tx, hub.options())
+}
+
+/// State of the client handlers without security, limits, origin checks or tenants.
+pub fn app_state(hub: Hub) -> AppState {
+    AppState {
+        hub,
+        version_number: 1,
+        auth: Arc::new(RwLock::new(Default::default())),
+        ratelimit: Arc::new(RwLock::new(None)),
+        origin: Arc::new(RwLock::new(None)),
+        tenants: Arc::new(RwLock::new(None)),
+    }
+}
@@ -0,0 +1,338 @@
+#![deny(unused_imports)]
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use anyhow::anyhow;
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::crypto::CryptoProvider;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use tklog::{error, warn};
+use tokio::time::Instant;
+use x509_parser::prelude::*;
+use crate::common::ParseError;
+use crate::config::{CertWatch, Config, TlsItem};
+use crate::reload::Shared;
+
+const DEFAULT_CHECK_INTERVAL: u64 = 60;
+const DEFAULT_EXPIRY_WARN_DAYS: u64 = 14;
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Picks the certificate of a tls port by the SNI hostname of the client.
+#[derive(Debug, Default)]
+struct SniResolver {
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.lookup(hello.server_name())
+    }
+}
+
+impl SniResolver {
+
+    fn new(items: &[TlsItem], provider: &CryptoProvider) -> anyhow::Result<Self> {
+        let mut resolver = Self::default();
+        for item in items {
+            let key = certified_key(item, provider)?;
+            for name in server_names(item)? {
+                resolver.by_name.entry(name.to_ascii_lowercase()).or_insert_with(|| key.clone());
+            }
+            if is_default(item, items) {
+                resolver.default = Some(key);
+            }
+        }
+        Ok(resolver)
+    }
+
+    fn lookup(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        if let Some(name) = server_name {
+            let name = name.to_ascii_lowercase();
+            if let Some(key) = self.by_name.get(&name) {
+                return Some(key.clone())
+            }
+            // a wildcard covers exactly one label
+            if let Some((_, parent)) = name.split_once('.') {
+                if let Some(key) = self.by_name.get(&format!("*.{}", parent)) {
+                    return Some(key.clone())
+                }
+            }
+        }
+        self.default.clone()
+    }
+}
+
+/// Loads the certificates of one port.
+pub fn load(items: &[TlsItem]) -> anyhow::Result<RustlsConfig> {
+    Ok(RustlsConfig::from_config(Arc::new(server_config(items)?)))
+}
+
+/// Replaces the certificates of a listening port, the current ones stay in use on error.
+pub fn reload(rustls: &RustlsConfig, items: &[TlsItem]) -> anyhow::Result<()> {
+    rustls.reload_from_config(Arc::new(server_config(items)?));
+    Ok(())
+}
+
+fn server_config(items: &[TlsItem]) -> anyhow::Result<ServerConfig> {
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(SniResolver::default()));
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    config.cert_resolver = Arc::new(SniResolver::new(items, config.crypto_provider())?);
+    Ok(config)
+}
+
+fn certified_key(item: &TlsItem, provider: &CryptoProvider) -> anyhow::Result<Arc<CertifiedKey>> {
+    let certs = rustls_pemfile::certs(&mut std::fs::read(&item.cert)?.as_slice())
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate in {}", item.cert))
+    }
+    let key = rustls_pemfile::private_key(&mut std::fs::read(&item.key)?.as_slice())?
+        .ok_or_else(|| anyhow!("no private key in {}", item.key))?;
+    let key = provider.key_provider.load_private_key(key)?;
+    let certified = CertifiedKey::new(certs, key);
+    // a half rotated pair would load fine and fail every handshake
+    certified.keys_match().map_err(|e| anyhow!("{} does not belong to {}: {}", item.key, item.cert, e))?;
+    Ok(Arc::new(certified))
+}
+
+/// Hostnames a cert is served for, the configured ones or else the DNS names in the cert.
+pub fn server_names(item: &TlsItem) -> anyhow::Result<Vec<String>> {
+    if let Some(names) = item.server_names.as_ref() {
+        return Ok(names.clone())
+    }
+    with_first_cert(&item.cert, |x509| {
+        let mut names = Vec::new();
+        if let Ok(Some(san)) = x509.subject_alternative_name() {
+            for name in san.value.general_names.iter() {
+                if let GeneralName::DNSName(name) = name {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        if names.is_empty() {
+            if let Some(cn) = x509.subject().iter_common_name().next().and_then(|cn| cn.as_str().ok()) {
+                names.push(cn.to_string());
+            }
+        }
+        Ok(names)
+    })
+}
+
+/// Whether a cert is served to clients whose hostname matches no cert of the port.
+pub fn is_default(item: &TlsItem, port_items: &[TlsItem]) -> bool {
+    item.default.unwrap_or(port_items.len() == 1)
+}
+
+/// Unix timestamp after which the first certificate of a PEM file is no longer valid.
+pub fn cert_not_after(file_name: &str) -> anyhow::Result<i64> {
+    with_first_cert(file_name, |x509| Ok(x509.validity().not_after.timestamp()))
+}
+
+fn with_first_cert<T>(file_name: &str, f: impl FnOnce(&X509Certificate) -> anyhow::Result<T>) -> anyhow::Result<T> {
+    let data = std::fs::read(file_name)?;
+    if let Some(pem) = Pem::iter_from_buffer(&data).next() {
+        let pem = pem?;
+        let x509 = pem.parse_x509()?;
+        return f(&x509)
+    }
+    Err(ParseError::Parse(file_name.to_string(), "not found".to_string()).into())
+}
+
+type Stamp = (Option<SystemTime>, Option<SystemTime>);
+
+/// Reloads the certificates of a tls port in place when one of their files changes.
+pub struct CertWatcher {
+    config: Shared<Config>,
+    tls: Arc<Vec<(u16, RustlsConfig)>>,
+    check_interval: Duration,
+    expiry_warn_days: u64,
+    // tls port -> modification times of the cert and key files last loaded
+    seen: HashMap<u16, Vec<Stamp>>,
+}
+
+impl CertWatcher {
+
+    pub fn new(config: Shared<Config>, tls: Vec<(u16, RustlsConfig)>, watch: Option<&CertWatch>) -> Self {
+        let mut s = Self {
+            config,
+            tls: Arc::new(tls),
+            check_interval: Duration::from_secs(watch.and_then(|w| w.check_interval).unwrap_or(DEFAULT_CHECK_INTERVAL)),
+            expiry_warn_days: watch.and_then(|w| w.expiry_warn_days).unwrap_or(DEFAULT_EXPIRY_WARN_DAYS),
+            seen: HashMap::new(),
+        };
+        // the certificates were just loaded
+        for (port, items) in s.config.read().unwrap().tls_ports() {
+            s.seen.insert(port, stamps(&items));
+        }
+        s
+    }
+
+    pub fn start(mut self) {
+        tokio::spawn(async move {
+            let mut timer = tokio::time::interval(self.check_interval);
+            let mut last_expiry_check: Option<Instant> = None;
+            loop {
+                timer.tick().await;
+                self.reload_changed();
+                if last_expiry_check.map(|t| t.elapsed() >= EXPIRY_CHECK_INTERVAL).unwrap_or(true) {
+                    self.check_expiry();
+                    last_expiry_check = Some(Instant::now());
+                }
+            }
+        });
+    }
+
+    fn reload_changed(&mut self) {
+        let ports = self.config.read().unwrap().tls_ports();
+        for (port, items) in ports {
+            let rustls = match self.tls.iter().find(|(p, _)| *p == port) {
+                None => continue,
+                Some((_, rustls)) => rustls,
+            };
+            let stamps = stamps(&items);
+            if self.seen.get(&port) == Some(&stamps) {
+                continue;
+            }
+            // a half written pair fails here and is retried once the other file changes
+            self.seen.insert(port, stamps);
+            match reload(rustls, &items) {
+                Ok(_) => warn!("certs of port", port, "reloaded"),
+                Err(e) => error!("reload certs of port", port, "failed, keep the current ones:", e),
+            }
+        }
+    }
+
+    fn check_expiry(&self) {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+        for item in self.config.read().unwrap().tls_items() {
+            match cert_not_after(&item.cert) {
+                Ok(not_after) => {
+                    let days = (not_after - now) / (24 * 60 * 60);
+                    if not_after < now {
+                        error!("cert", item.cert, "has expired");
+                    } else if days < self.expiry_warn_days as i64 {
+                        warn!("cert", item.cert, "expires in", days, "days");
+                    }
+                }
+                Err(e) => error!("read cert", item.cert, "failed:", e),
+            }
+        }
+    }
+}
+
+fn stamps(items: &[TlsItem]) -> Vec<Stamp> {
+    items.iter().map(|item| (modified(&item.cert), modified(&item.key))).collect()
+}
+
+fn modified(file_name: &str) -> Option<SystemTime> {
+    std::fs::metadata(file_name).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::UNIX_EPOCH;
+    use super::*;
+
+    fn testdata(name: &str) -> String {
+        format!("{}/testdata/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn item(cert: &str, key: &str, server_names: Option<&[&str]>, default: Option<bool>) -> TlsItem {
+        TlsItem {
+            port: 443,
+            cert: cert.to_string(),
+            key: key.to_string(),
+            server_names: server_names.map(|names| names.iter().map(|n| n.to_string()).collect()),
+            default,
+        }
+    }
+
+    fn provider() -> Arc<CryptoProvider> {
+        ServerConfig::builder().with_no_client_auth().with_cert_resolver(Arc::new(SniResolver::default())).crypto_provider().clone()
+    }
+
+    // the common name of the certificate the resolver picked
+    fn served(resolver: &SniResolver, server_name: Option<&str>) -> Option<String> {
+        let key = resolver.lookup(server_name)?;
+        let (_, x509) = parse_x509_certificate(key.end_entity_cert().unwrap()).unwrap();
+        let cn = x509.subject().iter_common_name().next().unwrap().as_str().unwrap().to_string();
+        Some(cn)
+    }
+
+    #[test]
+    fn certificates_are_picked_by_server_name() {
+        let items = [
+            item(&testdata("a.pem"), &testdata("a.key"), None, None),
+            item(&testdata("b.pem"), &testdata("b.key"), Some(&["signal.example.org"]), Some(true)),
+        ];
+        let resolver = SniResolver::new(&items, &provider()).unwrap();
+        assert_eq!(served(&resolver, Some("a.example.com")).as_deref(), Some("a.example.com"));
+        assert_eq!(served(&resolver, Some("SIGNAL.example.org")).as_deref(), Some("b.example.com"));
+        // the wildcard of the cert covers one label only
+        assert_eq!(served(&resolver, Some("www.a.example.com")).as_deref(), Some("a.example.com"));
+        assert_eq!(served(&resolver, Some("x.www.a.example.com")).as_deref(), Some("b.example.com"));
+        // configured names replace the names in the cert
+        assert_eq!(served(&resolver, Some("b.example.com")).as_deref(), Some("b.example.com"));
+        assert_eq!(served(&resolver, Some("unknown.example.net")).as_deref(), Some("b.example.com"));
+        assert_eq!(served(&resolver, None).as_deref(), Some("b.example.com"));
+    }
+
+    #[test]
+    fn unknown_names_get_no_certificate_without_a_default() {
+        let items = [
+            item(&testdata("a.pem"), &testdata("a.key"), None, None),
+            item(&testdata("b.pem"), &testdata("b.key"), None, None),
+        ];
+        let resolver = SniResolver::new(&items, &provider()).unwrap();
+        assert_eq!(served(&resolver, Some("b.example.com")).as_deref(), Some("b.example.com"));
+        assert!(served(&resolver, Some("unknown.example.net")).is_none());
+        // the only cert of a port is its default
+        let resolver = SniResolver::new(&items[..1], &provider()).unwrap();
+        assert_eq!(served(&resolver, Some("unknown.example.net")).as_deref(), Some("a.example.com"));
+    }
+
+    fn write(path: &PathBuf, from: &str, modified: u64) {
+        std::fs::copy(from, path).unwrap();
+        // a distinct time for every write, the file system may not tell writes of one second apart
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(modified)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn half_written_pairs_are_skipped_until_complete() {
+        let dir = std::env::temp_dir().join(format!("cbsignal_tls_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("cert.key"));
+        write(&cert, &testdata("a.pem"), 1);
+        write(&key, &testdata("a.key"), 1);
+        let yaml = format!("
+log: {{ writers: stdout, logger_level: INFO, logger_dir: log, log_rotate_date: 1, log_rotate_size: 1 }}
+tls: {{ port: 443, cert: {}, key: {} }}
+", cert.display(), key.display());
+        let config: Config = serde_yaml::from_str(&yaml).unwrap();
+        let items = config.tls_items();
+        let rustls = load(&items).unwrap();
+        let mut watcher = CertWatcher::new(Arc::new(std::sync::RwLock::new(config)), vec![(443, rustls.clone())], None);
+        let loaded = rustls.get_inner();
+
+        // the new cert is in place, its key is not
+        write(&cert, &testdata("b.pem"), 2);
+        watcher.reload_changed();
+        assert!(Arc::ptr_eq(&loaded, &rustls.get_inner()));
+        // a key which is still empty
+        std::fs::write(&key, "").unwrap();
+        watcher.reload_changed();
+        assert!(Arc::ptr_eq(&loaded, &rustls.get_inner()));
+
+        write(&key, &testdata("b.key"), 3);
+        watcher.reload_changed();
+        assert!(!Arc::ptr_eq(&loaded, &rustls.get_inner()));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,9 +1,18 @@
 use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Result};
 use hmac::{Hmac, Mac};
 use md5::{Md5};
+use sha2::Sha256;
 use tklog::warn;
+use crate::config::Security;
 
 type HmacMd5 = Hmac<Md5>;
+type HmacSha256 = Hmac<Sha256>;
+
+// prefix of tokens in the format v2.<key id>.<timestamp>.<hex hmac-sha256>
+const TOKEN_V2: &str = "v2.";
+// hex chars of the truncated signature of legacy tokens
+const LEGACY_SIGN_LEN: usize = 8;
 
 pub fn get_version_num<S: AsRef<str>>(ver: S) -> i32 {
     let ver: &str = ver.as_ref();
@@ -13,45 +22,134 @@ pub fn get_version_num<S: AsRef<str>>(ver: S) -> i32 {
     major * 10 + minor
 }
 
+/// Verify the token of a peer, a malformed token is rejected like a wrong one.
 pub fn check_token(
     id: &str,
     query_token: Option<String>,
-    real_token: String,
-    max_timestamp_age: u64,
+    security: &Security,
 ) -> bool {
     let query_token = query_token.unwrap_or("".to_string());
-    if query_token.is_empty() || max_timestamp_age == 0 {
+    if query_token.is_empty() || security.maxTimeStampAge == 0 {
         return false;
     }
-
-    let tokens: Vec<&str> = query_token.split('-').collect();
-    if tokens.len() < 2 {
+    let result = match query_token.strip_prefix(TOKEN_V2) {
+        Some(token) => check_token_v2(id, token, security),
+        None => check_token_legacy(id, query_token.as_str(), security),
+    };
+    if let Err(e) = result {
+        warn!("token of", id, "rejected:", e);
         return false;
     }
+    true
+}
 
+// <key id>.<timestamp>.<hex hmac-sha256 of "v2.<key id>.<timestamp>.<peer id>">
+fn check_token_v2(id: &str, token: &str, security: &Security) -> Result<()> {
+    let mut parts = token.splitn(3, '.');
+    let (kid, ts_str, sign) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(kid), Some(ts), Some(sign)) => (kid, ts, sign),
+        _ => return Err(anyhow!("malformed token")),
+    };
+    let key = security.keys.iter().flatten()
+        .find(|key| key.id == kid)
+        .ok_or_else(|| anyhow!("unknown key id {}", kid))?;
+    check_timestamp(ts_str, security.maxTimeStampAge)?;
+    let mut hmac = HmacSha256::new_from_slice(key.secret.as_bytes())?;
+    hmac.update(format!("{}{}.{}.{}", TOKEN_V2, kid, ts_str, id).as_bytes());
+    hmac.verify_slice(&hex::decode(sign)?).map_err(|_| anyhow!("signature does not match"))
+}
+
+// <8 hex chars of hmac-md5 of "<timestamp><peer id>">-<timestamp>
+fn check_token_legacy(id: &str, token: &str, security: &Security) -> Result<()> {
+    let secret = security.token.as_ref().ok_or_else(|| anyhow!("legacy tokens are disabled"))?;
+    let tokens: Vec<&str> = token.split('-').collect();
+    if tokens.len() < 2 || tokens[0].len() != LEGACY_SIGN_LEN {
+        return Err(anyhow!("malformed token"));
+    }
+    let sign = tokens[0];
+    let ts_str = tokens[1];
+    check_timestamp(ts_str, security.maxTimeStampAge)?;
+    let mut hmac = HmacMd5::new_from_slice(secret.as_bytes())?;
+    hmac.update(ts_str.as_bytes());
+    hmac.update(id.as_bytes());
+    hmac.verify_truncated_left(&hex::decode(sign)?).map_err(|_| anyhow!("signature does not match"))
+}
+
+fn check_timestamp(ts_str: &str, max_timestamp_age: u64) -> Result<()> {
+    let ts = ts_str.parse::<u64>()?;
     let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
         .as_secs();
-    let sign = tokens[0];
-    let ts_str = tokens[1];
-    let ts = ts_str.parse::<u64>().unwrap();
-
     if ts < now.saturating_sub(max_timestamp_age) || ts > now + max_timestamp_age {
-        warn!("ts expired for", now - ts);
-        return false;
+        return Err(anyhow!("timestamp {} is {}s away from now", ts, now.abs_diff(ts)));
     }
+    Ok(())
+}
 
-    let mut hmac = HmacMd5::new_from_slice(real_token.as_bytes()).unwrap();
-    hmac.update(ts_str.as_bytes());
-    hmac.update(id.as_bytes());
-    let real_sign = hex::encode(&hmac.finalize().into_bytes())[..8].to_string();
+#[cfg(test)]
+mod tests {
+    use crate::config::{SecurityKey, SecurityMode};
+    use crate::testing::{now, PEER};
+    use super::*;
 
-    if sign != real_sign {
-        warn!("token not match, sign", sign, "real_sign", real_sign);
-        return false;
+    fn security() -> Security {
+        Security {
+            enable: true,
+            mode: SecurityMode::Token,
+            maxTimeStampAge: 60,
+            token: Some("legacy-secret".to_string()),
+            keys: Some(vec![SecurityKey { id: "k1".to_string(), secret: "v2-secret".to_string() }]),
+            jwt: None,
+        }
     }
 
-    true
-}
+    fn v2_token(kid: &str, secret: &str, ts: &str, id: &str) -> String {
+        let mut hmac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+        hmac.update(format!("{}{}.{}.{}", TOKEN_V2, kid, ts, id).as_bytes());
+        format!("{}{}.{}.{}", TOKEN_V2, kid, ts, hex::encode(hmac.finalize().into_bytes()))
+    }
+
+    fn legacy_token(secret: &str, ts: &str, id: &str) -> String {
+        let mut hmac = HmacMd5::new_from_slice(secret.as_bytes()).unwrap();
+        hmac.update(ts.as_bytes());
+        hmac.update(id.as_bytes());
+        let sign = hex::encode(hmac.finalize().into_bytes());
+        format!("{}-{}", &sign[..LEGACY_SIGN_LEN], ts)
+    }
 
+    #[test]
+    fn v2_tokens_are_verified() {
+        let security = security();
+        let ts = now().to_string();
+        let check = |token: String| check_token(PEER, Some(token), &security);
+        assert!(check(v2_token("k1", "v2-secret", &ts, PEER)));
+        // unknown key id
+        assert!(!check(v2_token("k2", "v2-secret", &ts, PEER)));
+        // bad mac, signed with another secret or for another peer
+        assert!(!check(v2_token("k1", "wrong", &ts, PEER)));
+        assert!(!check(v2_token("k1", "v2-secret", &ts, "peer000002")));
+        assert!(!check(format!("{}k1.{}.zz", TOKEN_V2, ts)));
+        // stale or from the future
+        assert!(!check(v2_token("k1", "v2-secret", &(now() - 120).to_string(), PEER)));
+        assert!(!check(v2_token("k1", "v2-secret", &(now() + 120).to_string(), PEER)));
+        // a timestamp which is not a number is rejected instead of panicking
+        assert!(!check(v2_token("k1", "v2-secret", "soon", PEER)));
+        assert!(!check(format!("{}k1", TOKEN_V2)));
+        assert!(!check_token(PEER, None, &security));
+    }
+
+    #[test]
+    fn legacy_tokens_are_accepted_while_configured() {
+        let mut security = security();
+        let ts = now().to_string();
+        let token = legacy_token("legacy-secret", &ts, PEER);
+        assert!(check_token(PEER, Some(token.clone()), &security));
+        assert!(!check_token("peer000002", Some(token.clone()), &security));
+        assert!(!check_token(PEER, Some(legacy_token("legacy-secret", "x1", PEER)), &security));
+        assert!(!check_token(PEER, Some(legacy_token("legacy-secret", &(now() - 120).to_string(), PEER)), &security));
+        assert!(!check_token(PEER, Some("abc-123".to_string()), &security));
+        security.token = None;
+        assert!(!check_token(PEER, Some(token), &security));
+    }
+}